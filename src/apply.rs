@@ -1,62 +1,226 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Error};
 use owo_colors::OwoColorize;
+use serde::Deserialize;
 
 use crate::{
-    command::{run_command, run_command_without_local_path},
-    config::SshConfig,
+    command::{CommandRunner, EnvRunner, Invocation},
+    config::{
+        AptPackage, AptRepository, BleShConfig, Configuration, CurlInstaller, HOME_MANAGER,
+        OhMyPosh, PromptConfig, ShellConfig, SshConfig, ZoxideConfig, home_manager_flake,
+        nix_installable,
+    },
     git::extract_version,
+    lock::Lock,
+    shell::{Shell, install_init_script},
+    teardown::Teardown,
 };
 
+/// Shared system state that only one setup step may modify at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// The apt/dpkg lock.
+    Dpkg,
+    /// The terminal, for steps that prompt the user. Their output is not
+    /// labeled, so prompts without a trailing newline show up.
+    Terminal,
+}
+
 #[derive(Debug)]
 pub enum SetupStep<'a> {
-    AptGet(&'a [String]),
+    /// Sets up the apt repositories and installs the `apt-get` packages.
+    AptGet(&'a Configuration),
     Pkgx(&'a HashMap<String, String>),
-    Curl(&'a HashMap<String, String>),
+    Curl(&'a HashMap<String, CurlInstaller>),
     Mise(&'a HashMap<String, String>),
-    BleSh(bool),
+    BleSh(&'a BleShConfig),
     Nix(&'a HashMap<String, String>),
     Stow(&'a HashMap<String, String>),
-    OhMyPosh(&'a str),
-    Zoxide(bool),
-    Alias(&'a HashMap<String, String>),
+    Zoxide(&'a ZoxideConfig),
     Ssh(&'a SshConfig),
     Paths,
     Tailscale(bool),
-    Neofetch(bool),
     Doppler(bool),
     Npm(&'a HashMap<String, String>),
+    /// Installs oh-my-posh and its configuration file.
+    OhMyPosh(&'a OhMyPosh),
+    /// Installs starship and writes its configuration.
+    Starship(&'a PromptConfig),
+    /// Installs the configured shell, optionally as the login shell.
+    Shell(&'a ShellConfig),
+    /// Writes the shell init script and sources it from the shell's rc file.
+    ShellInit(&'a Configuration),
+    Remove(Teardown),
 }
 
 impl<'a> SetupStep<'a> {
-    pub fn run(&self) -> Result<(), Error> {
+    /// The configuration key this step is derived from.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SetupStep::AptGet(_) => "apt-get",
+            SetupStep::Pkgx(_) => "pkgx",
+            SetupStep::Curl(_) => "curl",
+            SetupStep::Mise(_) => "mise",
+            SetupStep::BleSh(_) => "ble.sh",
+            SetupStep::Nix(_) => "nix",
+            SetupStep::Stow(_) => "stow",
+            SetupStep::Zoxide(_) => "zoxide",
+            SetupStep::Ssh(_) => "ssh",
+            SetupStep::Paths => "paths",
+            SetupStep::Tailscale(_) => "tailscale",
+            SetupStep::Doppler(_) => "doppler",
+            SetupStep::Npm(_) => "npm",
+            SetupStep::OhMyPosh(_) => "oh_my_posh",
+            SetupStep::Starship(_) => "starship",
+            SetupStep::Shell(_) => "shell",
+            SetupStep::ShellInit(_) => "shell-init",
+            SetupStep::Remove(teardown) => teardown.name(),
+        }
+    }
+
+    /// The configuration section whose changes this step applies.
+    pub fn section(&self) -> &'static str {
         match self {
-            SetupStep::AptGet(pkgs) => install_apt(pkgs),
-            SetupStep::Pkgx(map) => install_pkgx(map),
-            SetupStep::Curl(map) => run_curl_installers(map),
-            SetupStep::Mise(map) => setup_mise(map),
-            SetupStep::BleSh(enabled) => enable_blesh(*enabled),
-            SetupStep::Nix(map) => setup_nix(map),
-            SetupStep::Stow(map) => setup_stow(map),
-            SetupStep::OhMyPosh(theme) => setup_oh_my_posh(theme),
-            SetupStep::Zoxide(enabled) => enable_zoxide(*enabled),
-            SetupStep::Alias(map) => setup_alias(map),
-            SetupStep::Ssh(config) => setup_ssh(config),
+            SetupStep::Remove(teardown) => teardown.section(),
+            _ => self.name(),
+        }
+    }
+
+    /// Runs the step, checking the scripts of the built-in installers
+    /// against the sha256 pinned in `installers`, by step name.
+    pub fn run(
+        &self,
+        runner: &dyn CommandRunner,
+        installers: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let pinned = |name: &str| installers.get(name).map(String::as_str);
+        match self {
+            SetupStep::AptGet(cfg) => install_apt(runner, cfg),
+            SetupStep::Pkgx(map) => install_pkgx(runner, map, pinned("pkgx")),
+            SetupStep::Curl(map) => run_curl_installers(runner, map),
+            SetupStep::Mise(map) => setup_mise(runner, map),
+            SetupStep::BleSh(config) => setup_blesh(runner, config),
+            SetupStep::Nix(map) => setup_nix(runner, map, pinned("nix")),
+            SetupStep::Stow(map) => setup_stow(runner, map),
+            SetupStep::Zoxide(config) => setup_zoxide(runner, config, pinned("zoxide")),
+            SetupStep::Ssh(config) => setup_ssh(runner, config),
             SetupStep::Paths => setup_paths(),
-            SetupStep::Tailscale(enabled) => enable_tailscale(*enabled),
-            SetupStep::Neofetch(enabled) => enable_neofetch(*enabled),
-            SetupStep::Doppler(enabled) => enable_doppler(*enabled),
-            SetupStep::Npm(map) => setup_npm(map),
+            SetupStep::Tailscale(enabled) => {
+                enable_tailscale(runner, *enabled, pinned("tailscale"))
+            }
+            SetupStep::Doppler(enabled) => enable_doppler(runner, *enabled, pinned("doppler")),
+            SetupStep::Npm(map) => setup_npm(runner, map),
+            SetupStep::OhMyPosh(config) => setup_oh_my_posh(runner, config, pinned("oh_my_posh")),
+            SetupStep::Starship(config) => setup_starship(runner, config, pinned("starship")),
+            SetupStep::Shell(config) => install_shell(runner, config),
+            SetupStep::ShellInit(cfg) => install_init_script(cfg),
+            SetupStep::Remove(teardown) => teardown.run(runner),
         }
     }
 
-    pub fn format_dry_run(&self) -> String {
+    /// Names of the steps that must complete before this one, when they
+    /// are part of the same run. Removals run before the additions of the
+    /// same section.
+    pub fn dependencies(&self) -> &'static [&'static str] {
         match self {
-            SetupStep::AptGet(pkgs) => {
-                let pkg_list = pkgs
+            SetupStep::Paths => &[],
+            SetupStep::AptGet(_) => &["paths", "remove:apt-get", "remove:apt"],
+            // curl installers need curl, and pkgx/pkgm may come from the curl installer
+            SetupStep::Curl(_) => &["paths", "apt-get"],
+            SetupStep::Pkgx(_) => &["paths", "apt-get", "curl", "remove:pkgx"],
+            SetupStep::Mise(_) => &["paths", "apt-get", "remove:mise"],
+            SetupStep::Npm(_) => &["paths", "mise", "pkgx", "remove:npm"],
+            SetupStep::ShellInit(_) => &["paths"],
+            // the [curl] section may already install oh-my-posh and its themes
+            SetupStep::OhMyPosh(_) => &["paths", "curl", "stow"],
+            SetupStep::Stow(_) => &["paths", "apt-get", "remove:stow"],
+            SetupStep::BleSh(_) => &["paths", "apt-get", "remove:ble.sh"],
+            SetupStep::Nix(_) => &["paths", "apt-get", "remove:nix"],
+            SetupStep::Zoxide(_)
+            | SetupStep::Ssh(_)
+            | SetupStep::Tailscale(_)
+            | SetupStep::Doppler(_)
+            | SetupStep::Shell(_)
+            | SetupStep::Starship(_) => &["paths", "apt-get"],
+            // npm globals are uninstalled while node is still managed by mise
+            SetupStep::Remove(Teardown::Mise(_)) => &["remove:npm"],
+            SetupStep::Remove(_) => &[],
+        }
+    }
+
+    /// Resources this step must hold exclusively while it runs.
+    pub fn resources(&self) -> &'static [Resource] {
+        match self {
+            // ssh-keygen, `tailscale up` and `doppler login` are interactive
+            SetupStep::Ssh(_) => &[Resource::Terminal],
+            SetupStep::Tailscale(_) | SetupStep::Doppler(_) => {
+                &[Resource::Dpkg, Resource::Terminal]
+            }
+            SetupStep::AptGet(_)
+            | SetupStep::Shell(_)
+            | SetupStep::Remove(
+                Teardown::AptGet(_)
+                | Teardown::AptRepositories(_)
+                | Teardown::Tailscale
+                | Teardown::Doppler,
+            ) => &[Resource::Dpkg],
+            SetupStep::Mise(_) => &[Resource::Dpkg],
+            SetupStep::Paths
+            | SetupStep::BleSh(_)
+            | SetupStep::Zoxide(_)
+            | SetupStep::ShellInit(_)
+            | SetupStep::OhMyPosh(_)
+            | SetupStep::Starship(_)
+            | SetupStep::Remove(_)
+            | SetupStep::Pkgx(_)
+            | SetupStep::Curl(_)
+            | SetupStep::Nix(_)
+            | SetupStep::Stow(_)
+            | SetupStep::Npm(_) => &[],
+        }
+    }
+
+    /// Whether the step prompts the user, see [`Resource::Terminal`].
+    pub fn is_interactive(&self) -> bool {
+        self.resources().contains(&Resource::Terminal)
+    }
+
+    /// Describes the step for the dry-run plan. `runner` is only used to
+    /// inspect the current state of the system.
+    pub fn format_dry_run(&self, runner: &dyn CommandRunner) -> String {
+        match self {
+            SetupStep::AptGet(cfg) => {
+                let installed = installed_apt_packages(runner).unwrap_or_default();
+                let repositories = cfg
+                    .apt_repositories()
                     .iter()
-                    .map(|p| format!("  - {}", p.green()))
+                    .map(|(name, repository)| {
+                        format!("  - {}: {}", name.green(), repository.to_string().cyan())
+                    })
+                    .collect::<Vec<_>>();
+                let pkg_list = repositories
+                    .into_iter()
+                    .chain(cfg.apt_get.iter().flatten().map(|p| {
+                        match (
+                            is_apt_package_installed(p, &installed),
+                            installed.get(p.name()),
+                        ) {
+                            (true, _) => format!("  - {} {}", p, "(already installed)".italic()),
+                            (false, Some(version)) => format!(
+                                "  - {} {}",
+                                p.green(),
+                                format!("({} installed)", version).cyan()
+                            ),
+                            (false, None) => format!("  - {}", p.green()),
+                        }
+                    }))
                     .collect::<Vec<_>>()
                     .join("\n");
                 format!(
@@ -82,7 +246,7 @@ impl<'a> SetupStep<'a> {
             SetupStep::Curl(map) => {
                 let curl_list = map
                     .iter()
-                    .map(|(k, v)| format!("  - {}: {}", k.green(), v.cyan()))
+                    .map(|(k, v)| format!("  - {}: {}", k.green(), v.to_string().cyan()))
                     .collect::<Vec<_>>()
                     .join("\n");
                 format!(
@@ -105,26 +269,32 @@ impl<'a> SetupStep<'a> {
                     mise_list
                 )
             }
-            SetupStep::BleSh(enabled) => {
+            SetupStep::BleSh(config) => {
                 format!(
                     "{} {}\n  - Enabled: {}",
                     "BleSh".blue().bold(),
                     "(Enable ble.sh shell enhancements)".italic(),
-                    enabled.to_string().green()
+                    config.to_string().green()
                 )
             }
-            SetupStep::Zoxide(enabled) => {
+            SetupStep::Zoxide(config) => {
                 format!(
                     "{} {}\n  - Enabled: {}",
                     "Zoxide".blue().bold(),
                     "(Enable zoxide for directory navigation)".italic(),
-                    enabled.to_string().green()
+                    config.to_string().green()
                 )
             }
             SetupStep::Nix(map) => {
                 let nix_list = map
                     .iter()
-                    .map(|(k, v)| format!("  - {}: {}", k.green(), v.cyan()))
+                    .map(|(k, v)| {
+                        let installable = match k == HOME_MANAGER {
+                            true => format!("switch --flake {}", home_manager_flake(v)),
+                            false => nix_installable(k, v),
+                        };
+                        format!("  - {}: {}", k.green(), installable.cyan())
+                    })
                     .collect::<Vec<_>>()
                     .join("\n");
                 format!(
@@ -147,27 +317,6 @@ impl<'a> SetupStep<'a> {
                     stow_list
                 )
             }
-            SetupStep::OhMyPosh(theme) => {
-                format!(
-                    "{} {}\n  - Theme: {}",
-                    "OhMyPosh".blue().bold(),
-                    "(Setup Oh My Posh for shell prompt)".italic(),
-                    theme.green()
-                )
-            }
-            SetupStep::Alias(map) => {
-                let alias_list = map
-                    .iter()
-                    .map(|(k, v)| format!("  - {}: {}", k.green(), v.cyan()))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                format!(
-                    "{} {}\n{}",
-                    "Alias".blue().bold(),
-                    "(Setup shell aliases)".italic(),
-                    alias_list
-                )
-            }
             SetupStep::Paths => {
                 format!(
                     "{} {}\n{}",
@@ -202,14 +351,6 @@ impl<'a> SetupStep<'a> {
                     enabled.to_string().green()
                 )
             }
-            SetupStep::Neofetch(enabled) => {
-                format!(
-                    "{} {}\n  - Enabled: {}",
-                    "Neofetch".blue().bold(),
-                    "(Enable Neofetch on terminal startup)".italic(),
-                    enabled.to_string().green()
-                )
-            }
             SetupStep::Doppler(enabled) => {
                 format!(
                     "{} {}\n  - Enabled: {}",
@@ -231,28 +372,375 @@ impl<'a> SetupStep<'a> {
                     npm_list
                 )
             }
+            SetupStep::OhMyPosh(config) => {
+                format!(
+                    "{} {}\n  - Config: {}",
+                    "OhMyPosh".blue().bold(),
+                    "(Install oh-my-posh and its prompt configuration)".italic(),
+                    config.config_file().green()
+                )
+            }
+            SetupStep::Starship(config) => {
+                format!(
+                    "{} {}\n  - Prompt: {}",
+                    "Starship".blue().bold(),
+                    "(Install starship and its prompt configuration)".italic(),
+                    config.to_string().green()
+                )
+            }
+            SetupStep::Shell(config) => {
+                format!(
+                    "{} {}\n  - Shell: {}",
+                    "Shell".blue().bold(),
+                    "(Install the shell to integrate with)".italic(),
+                    config.to_string().green()
+                )
+            }
+            SetupStep::ShellInit(cfg) => {
+                let shell = cfg.shell();
+                format!(
+                    "{} {}\n  - ~/{} (sourced from ~/{})",
+                    "ShellInit".blue().bold(),
+                    "(Write the shell init script)".italic(),
+                    shell.init_script().green(),
+                    shell.rc_file()
+                )
+            }
+            SetupStep::Remove(teardown) => teardown.format_dry_run(),
+        }
+    }
+}
+
+/// Removes duplicate steps and sorts the rest topologically by their
+/// [`SetupStep::dependencies`]. Independent steps keep their original order.
+pub fn sort_steps(steps: Vec<SetupStep>) -> Result<Vec<SetupStep>, Error> {
+    let mut unique: Vec<SetupStep> = Vec::new();
+    for step in steps {
+        if !unique.iter().any(|s| s.name() == step.name()) {
+            unique.push(step);
+        }
+    }
+
+    let mut remaining = unique;
+    let mut sorted: Vec<SetupStep> = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let ready = remaining.iter().position(|step| {
+            step.dependencies()
+                .iter()
+                .all(|dep| !remaining.iter().any(|s| s.name() == *dep))
+        });
+        match ready {
+            Some(index) => sorted.push(remaining.remove(index)),
+            None => {
+                return Err(Error::msg(format!(
+                    "Circular dependency between setup steps: {}",
+                    remaining
+                        .iter()
+                        .map(|step| step.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+        }
+    }
+
+    Ok(sorted)
+}
+
+/// Where apt reads its sources and keyrings from.
+pub const APT_DIR: &str = "/etc/apt";
+
+fn install_apt(runner: &dyn CommandRunner, cfg: &Configuration) -> Result<(), Error> {
+    let held = Lock::load()?.apt_held.unwrap_or_default();
+    install_apt_in(runner, cfg, Path::new(APT_DIR), &held)
+}
+
+/// Installs the apt packages with the sources in `apt_dir`. `held` are the
+/// packages oh-my-droid put on hold in previous runs.
+fn install_apt_in(
+    runner: &dyn CommandRunner,
+    cfg: &Configuration,
+    apt_dir: &Path,
+    held: &[String],
+) -> Result<(), Error> {
+    let pkgs = cfg.apt_get.as_deref().unwrap_or_default();
+    update_apt_preferences(runner, cfg.apt_preferences(), apt_dir)?;
+    let installed = match pkgs.is_empty() {
+        true => HashMap::new(),
+        false => installed_apt_packages(runner)?,
+    };
+    let missing = pkgs
+        .iter()
+        .filter(|p| !is_apt_package_installed(p, &installed))
+        .collect::<Vec<_>>();
+    let outdated = outdated_apt_repositories(&cfg.apt_repositories(), apt_dir);
+    if missing.is_empty() && outdated.is_empty() {
+        return sync_apt_holds(runner, pkgs, held);
+    }
+
+    runner
+        .run_network("sudo", &["apt-get", "update"])
+        .context("Failed to run apt-get update")?;
+    if !outdated.is_empty() {
+        runner.run_network("sudo", &["apt-get", "install", "-y", "curl", "gpg"])?;
+        for (name, repository) in &outdated {
+            setup_apt_repository(runner, name, repository, apt_dir)?;
+        }
+        runner
+            .run_network("sudo", &["apt-get", "update"])
+            .context("Failed to run apt-get update")?;
+    }
+
+    let (without_recommends, with_recommends): (Vec<_>, Vec<_>) =
+        missing.into_iter().partition(|p| p.no_install_recommends());
+    for (pkgs, flags) in [
+        (with_recommends, vec![]),
+        (without_recommends, vec!["--no-install-recommends"]),
+    ] {
+        if pkgs.is_empty() {
+            continue;
+        }
+        // pinned versions are picked through the apt preferences
+        let mut args: Vec<&str> = vec!["apt-get", "install", "-y"];
+        args.extend(flags);
+        args.extend(pkgs.iter().map(|p| p.name()));
+        runner
+            .run_network("sudo", &args)
+            .context("Failed to run apt-get install")?;
+    }
+
+    sync_apt_holds(runner, pkgs, held)
+}
+
+/// The `dpkg-query` format listing the packages, see [`installed_apt_packages`].
+const DPKG_QUERY_FORMAT: &str = "-f=${db:Status-Abbrev} ${Package} ${Version}\n";
+
+/// The installed apt packages and their versions, according to dpkg.
+pub fn installed_apt_packages(
+    runner: &dyn CommandRunner,
+) -> Result<HashMap<String, String>, Error> {
+    let output = runner
+        .output("dpkg-query", &["-W", DPKG_QUERY_FORMAT])
+        .context("Failed to list the installed apt packages")?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            // the second letter of the status is `i` once installed
+            let installed = fields.next()?.chars().nth(1)? == 'i';
+            let (name, version) = (fields.next()?, fields.next()?);
+            installed.then(|| (name.to_string(), version.to_string()))
+        })
+        .collect())
+}
+
+/// Whether `package` is installed, at its pinned version if it has one.
+fn is_apt_package_installed(package: &AptPackage, installed: &HashMap<String, String>) -> bool {
+    installed.get(package.name()).is_some_and(|version| {
+        package
+            .version()
+            .is_none_or(|pinned| version_matches_pin(version, pinned))
+    })
+}
+
+/// Whether `version` matches `pin`, where `*` matches any characters as in
+/// apt preferences.
+fn version_matches_pin(version: &str, pin: &str) -> bool {
+    let mut parts = pin.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = version.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
         }
     }
+    rest.ends_with(last)
 }
 
-fn install_apt(pkgs: &[String]) -> Result<(), Error> {
+/// Holds the packages configured with `hold = true` and releases the other
+/// configured packages, if oh-my-droid held them (`held_by_us`). Holds set
+/// by hand are left alone.
+fn sync_apt_holds(
+    runner: &dyn CommandRunner,
+    pkgs: &[AptPackage],
+    held_by_us: &[String],
+) -> Result<(), Error> {
     if pkgs.is_empty() {
         return Ok(());
     }
+    let held = runner
+        .output("apt-mark", &["showhold"])
+        .context("Failed to list held packages")?;
+    let held = held.lines().map(str::trim).collect::<Vec<_>>();
+    for (command, pkgs) in [
+        (
+            "hold",
+            pkgs.iter()
+                .filter(|p| p.hold() && !held.contains(&p.name()))
+                .collect::<Vec<_>>(),
+        ),
+        (
+            "unhold",
+            pkgs.iter()
+                .filter(|p| {
+                    !p.hold()
+                        && held.contains(&p.name())
+                        && held_by_us.iter().any(|name| name == p.name())
+                })
+                .collect::<Vec<_>>(),
+        ),
+    ] {
+        if pkgs.is_empty() {
+            continue;
+        }
+        let mut args = vec!["apt-mark", command];
+        args.extend(pkgs.iter().map(|p| p.name()));
+        runner
+            .run("sudo", &args)
+            .context(format!("Failed to {command} apt packages"))?;
+    }
+    Ok(())
+}
 
-    run_command("sudo", &["apt-get", "update"]).context("Failed to run apt-get update")?;
-    if !Path::new("/etc/apt/sources.list.d/vscode.list").exists()
-        && !Path::new("/etc/apt/sources.list.d/vscode.sources").exists()
-    {
-        run_command("sudo", &["apt-get", "install", "-y", "wget", "curl", "gpg"])?;
-        run_command(
-            "bash",
-            &[
-                "-c",
-                "wget -qO- https://packages.microsoft.com/keys/microsoft.asc | sudo gpg --dearmor > packages.microsoft.gpg",
-            ],
-        )?;
-        run_command(
+/// Writes the version pins to `preferences.d/oh-my-droid.pref` in `apt_dir`,
+/// or removes the file when nothing is pinned anymore.
+fn update_apt_preferences(
+    runner: &dyn CommandRunner,
+    preferences: Option<String>,
+    apt_dir: &Path,
+) -> Result<(), Error> {
+    let path = apt_dir.join("preferences.d/oh-my-droid.pref");
+    let current = std::fs::read_to_string(&path).ok();
+    if current == preferences {
+        return Ok(());
+    }
+    let Some(preferences) = preferences else {
+        return runner
+            .run("sudo", &["rm", "-f", &path.to_string_lossy()])
+            .context("Failed to remove the apt preferences");
+    };
+
+    let dir = PrivateDir::new("apt-preferences")?;
+    let tmp = dir.path().join("oh-my-droid.pref");
+    std::fs::write(&tmp, preferences)?;
+    let result = runner.run(
+        "sudo",
+        &[
+            "install",
+            "-D",
+            "-o",
+            "root",
+            "-g",
+            "root",
+            "-m",
+            "644",
+            &tmp.to_string_lossy(),
+            &path.to_string_lossy(),
+        ],
+    );
+    result.context("Failed to write the apt preferences")
+}
+
+/// The repositories whose sources file in `apt_dir` is missing or differs
+/// from the configuration, or whose keyring is missing.
+fn outdated_apt_repositories(
+    repositories: &[(String, AptRepository)],
+    apt_dir: &Path,
+) -> Vec<(String, AptRepository)> {
+    repositories
+        .iter()
+        .filter(|(name, repository)| {
+            let keyring = apt_dir.join(format!("keyrings/{name}.gpg"));
+            let sources = apt_dir.join(format!("sources.list.d/{name}.sources"));
+            let expected = repository.sources(&keyring.to_string_lossy());
+            !keyring.exists() || std::fs::read_to_string(sources).ok() != Some(expected)
+        })
+        .cloned()
+        .collect()
+}
+
+fn setup_apt_repository(
+    runner: &dyn CommandRunner,
+    name: &str,
+    repository: &AptRepository,
+    apt_dir: &Path,
+) -> Result<(), Error> {
+    let work_dir = PrivateDir::new(&format!("apt-{name}"))?;
+    install_apt_repository(runner, name, repository, apt_dir, work_dir.path())
+        .context(format!("Failed to set up apt repository {name}"))
+}
+
+/// Downloads the repository's signing key, checks its fingerprint and
+/// installs it along with a deb822 sources file into `apt_dir`. `work_dir`
+/// is a scratch directory, also used as the gpg home.
+fn install_apt_repository(
+    runner: &dyn CommandRunner,
+    name: &str,
+    repository: &AptRepository,
+    apt_dir: &Path,
+    work_dir: &Path,
+) -> Result<(), Error> {
+    let key = work_dir.join("key").to_string_lossy().to_string();
+    let keyring = work_dir
+        .join(format!("{name}.gpg"))
+        .to_string_lossy()
+        .to_string();
+    let home = work_dir.to_string_lossy().to_string();
+    let fingerprint = repository.normalized_fingerprint();
+
+    runner
+        .run_network("curl", &["-fsSL", "-o", &key, &repository.key_url])
+        .context(format!("Failed to download the signing key of {name}"))?;
+    let keys = runner
+        .output("gpg", &["--show-keys", "--with-colons", &key])
+        .context(format!("Failed to read the signing key of {name}"))?;
+    let fingerprints = key_fingerprints(&keys);
+    if !fingerprints.contains(&fingerprint) {
+        return Err(Error::msg(format!(
+            "The signing key of apt repository {} has fingerprint {}, expected {}",
+            name,
+            fingerprints.join(", "),
+            fingerprint
+        )));
+    }
+    // only the expected key ends up in the keyring
+    runner.run(
+        "gpg",
+        &["--batch", "--quiet", "--homedir", &home, "--import", &key],
+    )?;
+    runner.run(
+        "gpg",
+        &[
+            "--batch",
+            "--quiet",
+            "--homedir",
+            &home,
+            "--output",
+            &keyring,
+            "--export",
+            &fingerprint,
+        ],
+    )?;
+
+    let keyring_path = apt_dir.join(format!("keyrings/{name}.gpg"));
+    let sources_path = apt_dir.join(format!("sources.list.d/{name}.sources"));
+    let sources = work_dir
+        .join(format!("{name}.sources"))
+        .to_string_lossy()
+        .to_string();
+    std::fs::write(
+        &sources,
+        repository.sources(&keyring_path.to_string_lossy()),
+    )?;
+    for (from, to) in [(&keyring, &keyring_path), (&sources, &sources_path)] {
+        runner.run(
             "sudo",
             &[
                 "install",
@@ -263,147 +751,689 @@ fn install_apt(pkgs: &[String]) -> Result<(), Error> {
                 "root",
                 "-m",
                 "644",
-                "packages.microsoft.gpg",
-                "/etc/apt/keyrings/packages.microsoft.gpg",
+                from,
+                &to.to_string_lossy(),
             ],
         )?;
-        run_command(
-            "bash",
-            &[
-                "-c",
-                "echo 'deb [arch=amd64,arm64,armhf signed-by=/etc/apt/keyrings/packages.microsoft.gpg] https://packages.microsoft.com/repos/code stable main' | sudo tee /etc/apt/sources.list.d/vscode.list",
-            ],
-        )?;
-        run_command("rm", &["-f", "packages.microsoft.gpg"])?;
-        run_command("sudo", &["apt-get", "update"]).context("Failed to run apt-get update")?;
     }
 
-    if !Path::new("/etc/apt/sources.list.d/mise.list").exists() {
-        run_command("bash", &[
-      "-c",
-      "wget -qO - https://mise.jdx.dev/gpg-key.pub | gpg --dearmor | sudo tee /etc/apt/keyrings/mise-archive-keyring.gpg 1> /dev/null
-"])?;
-        run_command(
-            "bash",
-            &[
-                "-c",
-                "echo 'deb [signed-by=/etc/apt/keyrings/mise-archive-keyring.gpg arch=amd64,arm64] https://mise.jdx.dev/deb stable main' | sudo tee /etc/apt/sources.list.d/mise.list",
-            ],
-        )?;
-        run_command("sudo", &["apt-get", "update"]).context("Failed to run apt-get update")?;
+    // one-line sources written by earlier versions conflict with the new file
+    let legacy = apt_dir.join(format!("sources.list.d/{name}.list"));
+    if legacy.exists() {
+        runner.run("sudo", &["rm", "-f", &legacy.to_string_lossy()])?;
     }
+    Ok(())
+}
 
-    let mut args: Vec<&str> = vec!["apt-get", "install", "-y"];
-    args.extend(pkgs.iter().map(|s| s.as_str()));
-    run_command("sudo", &args).context("Failed to run apt-get install")?;
+/// The fingerprints of the keys in `gpg --with-colons` output.
+fn key_fingerprints(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("fpr:"))
+        .filter_map(|line| line.split(':').nth(9))
+        .map(|fingerprint| fingerprint.to_uppercase())
+        .collect()
+}
 
-    run_command(
-        "sudo",
-        &["rm", "-rf", "/etc/apt/sources.list.d/vscode.list"],
-    )?;
+const PKGX_INSTALLER: &str = "https://pkgx.sh";
 
-    Ok(())
+fn install_pkgx(
+    runner: &dyn CommandRunner,
+    map: &HashMap<String, String>,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
+    let pkgm_installed = is_on_path("pkgm") || Path::new("/usr/local/bin/pkgm").exists();
+    install_pkgx_with(runner, map, sha256, pkgm_installed)
 }
 
-fn install_pkgx(map: &HashMap<String, String>) -> Result<(), Error> {
-    for (name, ver) in map {
-        run_command("pkgm", &["install", &format!("{name}@{ver}")])
+fn install_pkgx_with(
+    runner: &dyn CommandRunner,
+    map: &HashMap<String, String>,
+    sha256: Option<&str>,
+    pkgm_installed: bool,
+) -> Result<(), Error> {
+    if map.is_empty() {
+        return Ok(());
+    }
+    // the pkgx.sh installer also installs pkgm
+    if !pkgm_installed {
+        run_script(runner, "pkgx", PKGX_INSTALLER, sha256, &["sh"], &[])
+            .context("Failed to install pkgx")?;
+    }
+
+    // without a listing every package is (re)installed, pkgm skips the
+    // ones that are up to date anyway
+    let installed = runner
+        .output("pkgm", &["ls"])
+        .map(|output| parse_pkgm_list(&output))
+        .unwrap_or_default();
+    let outdated = match map.values().any(|ver| ver == "latest") {
+        true => runner
+            .output("pkgm", &["outdated"])
+            .map(|output| parse_pkgm_outdated(&output))
+            .unwrap_or_default(),
+        false => Vec::new(),
+    };
+    let mut packages = map.iter().collect::<Vec<_>>();
+    packages.sort();
+    for (name, ver) in packages {
+        let is_installed = |wanted: &str| {
+            installed.iter().any(|(project, version)| {
+                pkgx_project_matches(project, name) && pkgx_version_matches(version, wanted)
+            })
+        };
+        let spec = match ver.as_str() {
+            // installed packages are upgraded to the newer version pkgm reports
+            "latest" => match outdated
+                .iter()
+                .find(|(project, _)| pkgx_project_matches(project, name))
+            {
+                Some((_, latest)) => format!("{name}@{latest}"),
+                None if is_installed("latest") => continue,
+                None => name.to_string(),
+            },
+            _ if is_installed(ver) => continue,
+            _ => format!("{name}@{ver}"),
+        };
+        runner
+            .run_network("pkgm", &["install", &spec])
             .context(format!("Failed to install {name} via pkgx"))?;
     }
-    run_command("pkgm", &["uninstall", "curl"]).context("Failed to uninstall curl via pkgx")?;
     Ok(())
 }
 
-fn run_curl_installers(map: &HashMap<String, String>) -> Result<(), Error> {
-    for (name, url) in map {
-        run_command("bash", &["-c", &format!("curl -fsSL {} | bash -s", url)])
+/// The projects and versions in `pkgm ls` output, one install path like
+/// `/usr/local/pkgs/github.com/junegunn/fzf/v0.55.0` per line.
+fn parse_pkgm_list(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (_, path) = line.trim().split_once("/pkgs/")?;
+            let (project, version) = path.trim_end_matches('/').rsplit_once('/')?;
+            let version = version.strip_prefix('v')?;
+            Some((project.to_string(), version.to_string()))
+        })
+        .collect()
+}
+
+/// The projects in `pkgm outdated` output with the newer version available,
+/// from lines starting with the project and ending with the latest version,
+/// like `github.com/junegunn/fzf  0.55.0  0.56.3`.
+fn parse_pkgm_outdated(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let project = words.next()?.trim_end_matches(':');
+            let versions = words
+                .map(|word| word.trim_start_matches('v'))
+                .filter(|word| {
+                    word.starts_with(|c: char| c.is_ascii_digit())
+                        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+                })
+                .collect::<Vec<_>>();
+            // the installed version, then the latest one
+            match versions.as_slice() {
+                [.., installed, latest] if installed != latest => {
+                    Some((project.to_string(), latest.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Whether the `[pkgx]` entry `name`, a project like `neovim.io` or the
+/// last part of one like `fzf`, refers to `project`.
+fn pkgx_project_matches(project: &str, name: &str) -> bool {
+    project == name || project.rsplit('/').next() == Some(name)
+}
+
+/// Whether `installed` satisfies `wanted`, a version prefix like `14` or
+/// `14.1`, or `latest` for any installed version.
+fn pkgx_version_matches(installed: &str, wanted: &str) -> bool {
+    let wanted = wanted.trim_start_matches(['@', '=', 'v']);
+    wanted == "latest" || installed == wanted || installed.starts_with(&format!("{wanted}."))
+}
+
+fn run_curl_installers(
+    runner: &dyn CommandRunner,
+    map: &HashMap<String, CurlInstaller>,
+) -> Result<(), Error> {
+    let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
+    let mut installers = map.iter().collect::<Vec<_>>();
+    installers.sort_by_key(|(name, _)| name.as_str());
+    for (name, installer) in installers {
+        let script = installer.script();
+        if let Some(creates) = &script.creates
+            && expand_home(&home, creates).exists()
+        {
+            println!(
+                "{} Skipping {}, {} exists.",
+                "=>".green(),
+                name.green(),
+                creates
+            );
+            continue;
+        }
+
+        let env = script.env_vars();
+        // sudo resets the environment unless told to keep the variables
+        let preserve_env = format!(
+            "--preserve-env={}",
+            env.iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        let mut interpreter = Vec::new();
+        if script.sudo {
+            interpreter.push("sudo");
+            if !env.is_empty() {
+                interpreter.push(preserve_env.as_str());
+            }
+        }
+        interpreter.push(script.interpreter.unwrap_or_default().name());
+        let args = script.args.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let dir = PrivateDir::new(name)?;
+        download_script(runner, dir.path(), name, &script.url)
+            .and_then(|path| {
+                verify_and_run_script(
+                    runner,
+                    &path,
+                    &script.url,
+                    script.sha256.as_deref(),
+                    env,
+                    &interpreter,
+                    &args,
+                )
+            })
             .context(format!("Failed to run curl installer for {name}"))?;
     }
     Ok(())
 }
 
-fn setup_mise(map: &HashMap<String, String>) -> Result<(), Error> {
+/// A temporary directory only the current user can access, removed when
+/// dropped. It is created exclusively, like `mkdtemp`, so other local users
+/// can neither pre-create it nor swap the files in it.
+pub struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    pub fn new(name: &str) -> Result<Self, Error> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.mode(0o700);
+        for attempt in 0..100u32 {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.subsec_nanos())
+                .unwrap_or_default();
+            let path = std::env::temp_dir().join(format!(
+                "oh-my-droid-{}-{}-{:08x}",
+                name.replace(['/', ' '], "_"),
+                std::process::id(),
+                nanos.wrapping_add(attempt)
+            ));
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self(path)),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => {
+                    return Err(err).context("Failed to create a temporary directory");
+                }
+            }
+        }
+        Err(Error::msg("Failed to create a temporary directory"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The installer scripts of the built-in steps, by step name. Their sha256
+/// can be pinned in `[installers]`.
+pub const BUILTIN_INSTALLERS: [(&str, &str); 7] = [
+    ("doppler", DOPPLER_INSTALLER),
+    ("nix", NIX_INSTALLER),
+    ("oh_my_posh", OH_MY_POSH_INSTALLER),
+    ("pkgx", PKGX_INSTALLER),
+    ("starship", STARSHIP_INSTALLER),
+    ("tailscale", TAILSCALE_INSTALLER),
+    ("zoxide", ZOXIDE_INSTALLER),
+];
+
+/// Downloads the script at `url` and runs it with `interpreter` and `args`.
+/// The script is only run once completely downloaded, and refused when it
+/// does not match `sha256`.
+fn run_script(
+    runner: &dyn CommandRunner,
+    name: &str,
+    url: &str,
+    sha256: Option<&str>,
+    interpreter: &[&str],
+    args: &[&str],
+) -> Result<(), Error> {
+    let dir = PrivateDir::new(name)?;
+    let script = download_script(runner, dir.path(), name, url)?;
+    verify_and_run_script(runner, &script, url, sha256, Vec::new(), interpreter, args)
+}
+
+/// Checks the downloaded `script` against `sha256` and runs it. `env` is
+/// only set for the interpreter, not for the checksum. The script is not
+/// a network command: installers are not safe to retry or to time out.
+fn verify_and_run_script(
+    runner: &dyn CommandRunner,
+    script: &Path,
+    url: &str,
+    sha256: Option<&str>,
+    env: Vec<(String, String)>,
+    interpreter: &[&str],
+    args: &[&str],
+) -> Result<(), Error> {
+    if let Some(expected) = sha256 {
+        let actual = file_sha256(runner, script)?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(Error::msg(format!(
+                "Checksum mismatch for {url}: expected sha256 {expected}, got {actual}. Refusing to run it, use `oh-my-droid pin-installers` to accept the new script"
+            )));
+        }
+    }
+
+    let script = script.to_string_lossy();
+    let (program, interpreter_args) = interpreter
+        .split_first()
+        .ok_or_else(|| Error::msg("No interpreter given for the script"))?;
+    let mut command_args = interpreter_args.to_vec();
+    command_args.push(&script);
+    command_args.extend(args);
+    EnvRunner::new(runner, env, Vec::new()).run(program, &command_args)
+}
+
+/// Downloads the script at `url` to a file named after `name` in `dir`,
+/// which should be a [`PrivateDir`] so the script cannot be swapped
+/// between its checksum and its run.
+pub fn download_script(
+    runner: &dyn CommandRunner,
+    dir: &Path,
+    name: &str,
+    url: &str,
+) -> Result<PathBuf, Error> {
+    let script = dir.join(format!("{}.sh", name.replace(['/', ' '], "_")));
+    runner
+        .run_network("curl", &["-fsSL", "-o", &script.to_string_lossy(), url])
+        .context(format!("Failed to download {url}"))?;
+    Ok(script)
+}
+
+/// The sha256 of the downloaded `file`, in lowercase hex.
+pub fn file_sha256(runner: &dyn CommandRunner, file: &Path) -> Result<String, Error> {
+    let output = runner
+        .output("sha256sum", &[&file.to_string_lossy()])
+        .context(format!(
+            "Failed to compute the sha256 of {}",
+            file.display()
+        ))?;
+    output
+        .split_whitespace()
+        .next()
+        .map(str::to_lowercase)
+        .ok_or_else(|| Error::msg(format!("No sha256 computed for {}", file.display())))
+}
+
+fn setup_mise(runner: &dyn CommandRunner, map: &HashMap<String, String>) -> Result<(), Error> {
     if !Path::new("/usr/bin/mise").exists() {
-        run_command("sudo", &["apt-get", "install", "-y", "mise"])
+        runner
+            .run_network("sudo", &["apt-get", "install", "-y", "mise"])
             .context("Failed to install mise")?;
     }
 
-    run_command(
-        "bash",
-        &[
-            "-c",
-            "sed -i '/mise /d' ~/.bashrc || echo 'No existing mise line found in .bashrc'",
-        ],
-    )?;
-    run_command(
-        "bash",
-        &[
-            "-c",
-            "echo '\neval \"$(mise activate bash)\"' | tee -a ~/.bashrc",
-        ],
-    )?;
-
     for (tool, ver) in map {
-        run_command("mise", &["use", "-g", &format!("{tool}@{ver}")])
+        runner
+            .run_network("mise", &["use", "-g", &format!("{tool}@{ver}")])
             .context(format!("Failed to configure {tool} via mise"))?;
     }
     Ok(())
 }
 
-fn enable_blesh(enabled: bool) -> Result<(), Error> {
+/// Builds ble.sh at the configured version and installs it to the prefix.
+/// The checkout is kept to update it in place when the version changes.
+fn setup_blesh(runner: &dyn CommandRunner, config: &BleShConfig) -> Result<(), Error> {
+    if !config.enabled() {
+        return Ok(());
+    }
+
     let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
-    let blesh_path = home.join("ble.sh");
-    if enabled && !blesh_path.exists() {
-        run_command_without_local_path(
+    let source_dir = home.join(BleShConfig::SOURCE_DIR);
+    let source = source_dir.to_string_lossy();
+    if !source_dir.exists() {
+        runner.execute(&Invocation::new(
             "bash",
             &[
-                "-c", "rm -rf ~/.local/bin/gettext* && git clone --recursive --depth 1 --shallow-submodules https://github.com/akinomyoga/ble.sh.git",
+                "-c",
+                &format!("rm -rf ~/.local/bin/gettext* && git clone --recursive https://github.com/akinomyoga/ble.sh.git {source}"),
             ],
-        )
+        ).network())
         .context("Failed to clone ble.sh repository")?;
-        run_command_without_local_path("make", &["-C", "ble.sh"])
-            .context("Failed to build ble.sh")?;
-        run_command_without_local_path(
-            "bash",
+    }
+
+    let version = config.version();
+    runner
+        .run_network("git", &["-C", &source, "fetch", "origin", version])
+        .context(format!("Failed to fetch ble.sh {version}"))?;
+    runner
+        .run(
+            "git",
+            &["-C", &source, "checkout", "--detach", "FETCH_HEAD"],
+        )
+        .context(format!("Failed to check out ble.sh {version}"))?;
+    runner
+        .run_network(
+            "git",
             &[
-                "-c",
-                "grep 'source ble' ~/.bashrc || echo '\nsource ble.sh/out/ble.sh' | tee -a ~/.bashrc",
+                "-C",
+                &source,
+                "submodule",
+                "update",
+                "--init",
+                "--recursive",
             ],
         )
-        .context("Failed to add ble.sh to .bashrc")?;
-    }
-    Ok(())
-}
+        .context("Failed to update ble.sh submodules")?;
 
-fn enable_zoxide(enabled: bool) -> Result<(), Error> {
-    if enabled {
-        run_command("bash", &["-c", "curl -sSL https://raw.githubusercontent.com/ajeetdsouza/zoxide/main/install.sh | bash"])
-            .context("Failed to install zoxide")?;
-        run_command(
-            "bash",
+    let prefix = expand_home(&home, config.prefix());
+    runner
+        .run_without_local_path(
+            "make",
             &[
-                "-c",
-                "grep zoxide ~/.bashrc || echo '\neval \"$(zoxide init bash)\"' | tee -a ~/.bashrc",
+                "-C",
+                &source,
+                "install",
+                &format!("PREFIX={}", prefix.display()),
             ],
         )
-        .context("Failed to add zoxide initialization to .bashrc")?;
-    }
+        .context("Failed to build ble.sh")?;
     Ok(())
 }
 
-fn setup_nix(_map: &HashMap<String, String>) -> Result<(), Error> {
-    run_command(
-        "bash",
+const ZOXIDE_INSTALLER: &str =
+    "https://raw.githubusercontent.com/ajeetdsouza/zoxide/main/install.sh";
+
+/// Installs zoxide to `~/.local/bin`, unless the requested version, or any
+/// version when none is pinned, is already installed.
+fn setup_zoxide(
+    runner: &dyn CommandRunner,
+    config: &ZoxideConfig,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
+    if !config.enabled() {
+        return Ok(());
+    }
+
+    // `zoxide --version` prints e.g. "zoxide v0.9.8"
+    let installed = runner
+        .output("zoxide", &["--version"])
+        .ok()
+        .and_then(|output| {
+            output
+                .split_whitespace()
+                .last()
+                .map(|version| version.trim_start_matches('v').to_string())
+        });
+    match (installed.as_deref(), config.version()) {
+        (Some(_), None) => return Ok(()),
+        (Some(installed), Some(version)) if installed == version => return Ok(()),
+        _ => {}
+    }
+
+    match config.version() {
+        Some(version) => install_zoxide_release(runner, version),
+        None => run_script(runner, "zoxide", ZOXIDE_INSTALLER, sha256, &["bash"], &[]),
+    }
+    .context("Failed to install zoxide")
+}
+
+/// A GitHub release, as returned by the releases API.
+#[derive(Debug, Deserialize)]
+struct GithubRelease {
+    assets: Vec<GithubReleaseAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubReleaseAsset {
+    name: String,
+    browser_download_url: String,
+    /// The checksum GitHub computed for the asset, like `sha256:<hex>`.
+    digest: Option<String>,
+}
+
+/// Installs the musl build of zoxide `version` to `~/.local/bin`. The
+/// tarball is only extracted once it matches the sha256 of the release
+/// asset.
+fn install_zoxide_release(runner: &dyn CommandRunner, version: &str) -> Result<(), Error> {
+    let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
+    let name = format!(
+        "zoxide-{version}-{}-unknown-linux-musl.tar.gz",
+        std::env::consts::ARCH
+    );
+    let release_url =
+        format!("https://api.github.com/repos/ajeetdsouza/zoxide/releases/tags/v{version}");
+    let release = runner
+        .capture(&Invocation::new("curl", &["-fsSL", &release_url]).network())
+        .context(format!("Failed to get the zoxide {version} release"))?;
+    let release: GithubRelease = serde_yml::from_str(&release)
+        .context(format!("Failed to parse the zoxide {version} release"))?;
+    let asset = release
+        .assets
+        .iter()
+        .find(|asset| asset.name == name)
+        .ok_or_else(|| Error::msg(format!("No {name} in the zoxide {version} release")))?;
+    let expected = asset
+        .digest
+        .as_deref()
+        .and_then(|digest| digest.strip_prefix("sha256:"))
+        .ok_or_else(|| Error::msg(format!("No sha256 published for {name}")))?;
+
+    let dir = PrivateDir::new("zoxide")?;
+    let tarball = dir.path().join(&name);
+    let tarball_path = tarball.to_string_lossy();
+    runner
+        .run_network(
+            "curl",
+            &["-fsSL", "-o", &tarball_path, &asset.browser_download_url],
+        )
+        .context(format!("Failed to download {}", asset.browser_download_url))?;
+    let actual = file_sha256(runner, &tarball)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(Error::msg(format!(
+            "Checksum mismatch for {}: expected sha256 {expected}, got {actual}",
+            asset.browser_download_url
+        )));
+    }
+
+    let bin_dir = home.join(".local/bin");
+    runner.run(
+        "tar",
         &[
-            "-c",
-            "type nix || curl -fsSL https://install.determinate.systems/nix | sh -s -- install --determinate",
+            "-xzf",
+            &tarball_path,
+            "-C",
+            &bin_dir.to_string_lossy(),
+            "zoxide",
         ],
     )
-    .context("Failed to install nix")?;
-    Ok(())
 }
 
-fn setup_stow(map: &HashMap<String, String>) -> Result<(), Error> {
+const NIX_INSTALLER: &str = "https://install.determinate.systems/nix";
+const NIX_BIN: &str = "/nix/var/nix/profiles/default/bin/nix";
+
+fn setup_nix(
+    runner: &dyn CommandRunner,
+    map: &HashMap<String, String>,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
+    if !is_on_path("nix") && !Path::new(NIX_BIN).exists() {
+        run_script(
+            runner,
+            "nix",
+            NIX_INSTALLER,
+            sha256,
+            &["sh"],
+            &["install", "--determinate"],
+        )
+        .context("Failed to install nix")?;
+    }
+    if map.is_empty() {
+        return Ok(());
+    }
+
+    let profile = nix_profile(runner)?;
+    let mut packages = map
+        .iter()
+        .filter(|(name, _)| *name != HOME_MANAGER)
+        .collect::<Vec<_>>();
+    packages.sort();
+    for (name, value) in packages {
+        let installable = nix_installable(name, value);
+        if profile.iter().any(|element| element.provides(&installable)) {
+            continue;
+        }
+        // the package is installed from another flake, replace it
+        if profile.iter().any(|element| element.name == *name) {
+            runner
+                .run("nix", &["profile", "remove", name])
+                .context(format!("Failed to remove {name} from the nix profile"))?;
+        }
+        runner
+            .run_network("nix", &["profile", "install", &installable])
+            .context(format!("Failed to install {installable} via nix"))?;
+    }
+
+    match map.get(HOME_MANAGER) {
+        Some(flake) => setup_home_manager(runner, &home_manager_flake(flake)),
+        None => Ok(()),
+    }
+}
+
+/// Applies the Home Manager configuration `flake`. Home Manager is run from
+/// its flake until the configuration installs it.
+fn setup_home_manager(runner: &dyn CommandRunner, flake: &str) -> Result<(), Error> {
+    let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
+    let installed = [
+        ".nix-profile/bin/home-manager",
+        ".local/state/nix/profile/bin/home-manager",
+    ]
+    .iter()
+    .map(|path| home.join(path))
+    .find(|path| path.exists());
+    match installed {
+        Some(path) => runner.run_network(&path.to_string_lossy(), &["switch", "--flake", flake]),
+        None => runner.run_network(
+            "nix",
+            &[
+                "run",
+                "home-manager/master",
+                "--",
+                "switch",
+                "--flake",
+                flake,
+            ],
+        ),
+    }
+    .context(format!(
+        "Failed to apply the Home Manager configuration {flake}"
+    ))
+}
+
+/// An element of the default nix profile, as listed by `nix profile list`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct NixProfileElement {
+    name: String,
+    /// e.g. `legacyPackages.x86_64-linux.ripgrep`
+    attr_path: String,
+    /// e.g. `flake:nixpkgs`
+    original_url: String,
+    store_paths: Vec<String>,
+}
+
+impl NixProfileElement {
+    /// Whether the element was installed from `installable`.
+    fn provides(&self, installable: &str) -> bool {
+        let (url, attr) = installable
+            .split_once('#')
+            .unwrap_or((installable, "default"));
+        let original_url = self.original_url.trim_start_matches("flake:");
+        original_url == url.trim_start_matches("flake:")
+            && (self.attr_path == attr || self.attr_path.ends_with(&format!(".{attr}")))
+    }
+}
+
+fn nix_profile(runner: &dyn CommandRunner) -> Result<Vec<NixProfileElement>, Error> {
+    let output = runner
+        .output("nix", &["profile", "list"])
+        .context("Failed to list the nix profile")?;
+    Ok(parse_nix_profile(&output))
+}
+
+/// Parses the `Name:`, `Flake attribute:`, ... blocks of `nix profile list`.
+fn parse_nix_profile(output: &str) -> Vec<NixProfileElement> {
+    let mut elements: Vec<NixProfileElement> = Vec::new();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match key.trim() {
+            "Name" => elements.push(NixProfileElement {
+                name: value,
+                ..Default::default()
+            }),
+            "Flake attribute" => {
+                if let Some(element) = elements.last_mut() {
+                    element.attr_path = value;
+                }
+            }
+            "Original flake URL" => {
+                if let Some(element) = elements.last_mut() {
+                    element.original_url = value;
+                }
+            }
+            "Store paths" => {
+                if let Some(element) = elements.last_mut() {
+                    element.store_paths = value.split_whitespace().map(String::from).collect();
+                }
+            }
+            _ => {}
+        }
+    }
+    elements
+}
+
+/// The store paths the `[nix]` packages are installed at, for the lock file.
+pub fn nix_store_paths(
+    runner: &dyn CommandRunner,
+    map: &HashMap<String, String>,
+) -> Result<HashMap<String, Vec<String>>, Error> {
+    let profile = nix_profile(runner)?;
+    Ok(map
+        .iter()
+        .filter(|(name, _)| *name != HOME_MANAGER)
+        .filter_map(|(name, value)| {
+            let installable = nix_installable(name, value);
+            let element = profile
+                .iter()
+                .find(|element| element.provides(&installable))?;
+            Some((name.clone(), element.store_paths.clone()))
+        })
+        .collect())
+}
+
+fn setup_stow(runner: &dyn CommandRunner, map: &HashMap<String, String>) -> Result<(), Error> {
     if map.is_empty() {
         return Ok(());
     }
@@ -425,97 +1455,56 @@ fn setup_stow(map: &HashMap<String, String>) -> Result<(), Error> {
     let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
 
     if !Path::new(&home.join(".dotfiles")).exists() {
-        run_command("bash", &["-c", &format!("git clone {} ~/.dotfiles", repo)])
+        runner
+            .run_network("bash", &["-c", &format!("git clone {} ~/.dotfiles", repo)])
             .context("Failed to clone dotfiles repository")?;
     } else {
-        run_command("bash", &["-c", "git -C ~/.dotfiles pull"])
+        runner
+            .run_network("bash", &["-c", "git -C ~/.dotfiles pull"])
             .context("Failed to update dotfiles repository")?;
     }
 
     if let Some(version) = version {
-        run_command("bash", &["-c", "git -C ~/.dotfiles fetch --all"])?;
-        run_command(
-            "bash",
-            &["-c", &format!("git -C ~/.dotfiles checkout {}", version)],
-        )
-        .context("Failed to checkout dotfiles version")?;
-        run_command("bash", &["-c", "git -C ~/.dotfiles pull"])
+        runner.run_network("bash", &["-c", "git -C ~/.dotfiles fetch --all"])?;
+        runner
+            .run(
+                "bash",
+                &["-c", &format!("git -C ~/.dotfiles checkout {}", version)],
+            )
+            .context("Failed to checkout dotfiles version")?;
+        runner
+            .run_network("bash", &["-c", "git -C ~/.dotfiles pull"])
             .context("Failed to update dotfiles repository")?;
     }
 
-    run_command("bash", &["-c", "stow -d ~/.dotfiles -t ~ -- ."])
+    runner
+        .run("bash", &["-c", "stow -d ~/.dotfiles -t ~ -- ."])
         .context("Failed to stow dotfiles")?;
 
     Ok(())
 }
 
-fn setup_oh_my_posh(theme: &str) -> Result<(), Error> {
-    run_command(
-        "bash",
-        &[
-            "-c",
-            "sed -i '/oh-my-posh/d' ~/.bashrc || echo 'No existing oh-my-posh line found in .bashrc'",
-        ],
-    )?;
-    run_command("bash", &["-c", &format!("echo 'eval \"$(oh-my-posh init bash --config $HOME/.cache/oh-my-posh/themes/{}.omp.json)\"' >> ~/.bashrc", theme)])
-        .context("Failed to set up Oh My Posh")?;
-    Ok(())
-}
-
-fn setup_alias(map: &HashMap<String, String>) -> Result<(), Error> {
-    for (alias, command) in map {
-        run_command(
-            "bash",
-            &["-c", &format!("sed -i '/alias {}/d' ~/.bashrc", alias)],
-        )?;
-        run_command(
-            "bash",
-            &[
-                "-c",
-                &format!("echo 'alias {}=\"{}\"' >> ~/.bashrc", alias, command),
-            ],
-        )
-        .context(format!(
-            "Failed to set up alias {} for command {}",
-            alias, command
-        ))?;
-    }
-    Ok(())
-}
-
 fn setup_paths() -> Result<(), Error> {
     let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
     let local_bin = home.join(".local/bin");
     if !local_bin.exists() {
         std::fs::create_dir_all(&local_bin).context("Failed to create ~/.local/bin directory")?;
     }
-
-    run_command(
-        "bash",
-        &["-c", "grep -q 'export PATH=\"$HOME/.local/bin:$PATH\"' ~/.bashrc || echo 'export PATH=\"$HOME/.local/bin:$PATH\"' >> ~/.bashrc"],
-    )
-    .context("Failed to add ~/.local/bin to PATH in .bashrc")?;
-
-    run_command(
-        "bash",
-        &["-c", "grep -q 'export PATH=\"/nix/var/nix/profiles/default/bin:$PATH\"' ~/.bashrc || echo 'export PATH=\"/nix/var/nix/profiles/default/bin:$PATH\"' >> ~/.bashrc"],
-    )
-    .context("Failed to add /nix/var/nix/profiles/default/bin to PATH in .bashrc")?;
-
     Ok(())
 }
 
-fn setup_ssh(config: &SshConfig) -> Result<(), Error> {
+fn setup_ssh(runner: &dyn CommandRunner, config: &SshConfig) -> Result<(), Error> {
     let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
     let ssh_dir = home.join(".ssh");
     if !ssh_dir.exists() {
         std::fs::create_dir_all(&ssh_dir).context("Failed to create ~/.ssh directory")?;
-        run_command("chmod", &["700", ssh_dir.to_str().unwrap()])
+        runner
+            .run("chmod", &["700", ssh_dir.to_str().unwrap()])
             .context("Failed to set permissions for ~/.ssh directory")?;
     }
 
     if let Some(port) = config.port {
-        run_command(
+        runner.run(
             "bash",
             &[
                 "-c",
@@ -525,13 +1514,14 @@ fn setup_ssh(config: &SshConfig) -> Result<(), Error> {
             ],
         )
         .context("Failed to update SSH config with port")?;
-        run_command("sudo", &["systemctl", "reload", "ssh"])
+        runner
+            .run("sudo", &["systemctl", "reload", "ssh"])
             .context("Failed to restart SSH service")?;
     }
 
     if let Some(authorized_keys) = &config.authorized_keys {
         for key in authorized_keys {
-            run_command(
+            runner.run(
                 "bash",
                 &["-c", &format!("echo '{}' > ~/.ssh/authorized_keys", key)],
             )?;
@@ -543,63 +1533,949 @@ fn setup_ssh(config: &SshConfig) -> Result<(), Error> {
         return Ok(());
     }
 
-    run_command("ssh-keygen", &["-t", "ed25519"]).context("Failed to generate SSH key")?;
+    runner
+        .run("ssh-keygen", &["-t", "ed25519"])
+        .context("Failed to generate SSH key")?;
 
     Ok(())
 }
 
-fn enable_tailscale(enabled: bool) -> Result<(), Error> {
+const TAILSCALE_INSTALLER: &str = "https://tailscale.com/install.sh";
+
+fn enable_tailscale(
+    runner: &dyn CommandRunner,
+    enabled: bool,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
     if enabled {
-        run_command(
-            "bash",
-            &["-c", "curl -fsSL https://tailscale.com/install.sh | sh"],
+        run_script(
+            runner,
+            "tailscale",
+            TAILSCALE_INSTALLER,
+            sha256,
+            &["sh"],
+            &[],
         )
         .context("Failed to install Tailscale")?;
-        run_command("bash", &["-c", "sudo tailscale up"]).context("Failed to enable Tailscale")?;
-        run_command("bash", &["-c", "sudo tailscale ip"])
+        runner
+            .run("bash", &["-c", "sudo tailscale up"])
+            .context("Failed to enable Tailscale")?;
+        runner
+            .run("bash", &["-c", "sudo tailscale ip"])
             .context("Failed to check Tailscale status")?;
     }
     Ok(())
 }
 
-fn enable_neofetch(enabled: bool) -> Result<(), Error> {
+const DOPPLER_INSTALLER: &str = "https://cli.doppler.com/install.sh";
+
+fn enable_doppler(
+    runner: &dyn CommandRunner,
+    enabled: bool,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
     if enabled {
-        run_command(
-            "bash",
-            &[
-                "-c",
-                "grep -q 'neofetch' ~/.bashrc || echo 'neofetch' >> ~/.bashrc",
-            ],
+        run_script(
+            runner,
+            "doppler",
+            DOPPLER_INSTALLER,
+            sha256,
+            &["sudo", "sh"],
+            &[],
         )
-        .context("Failed to add neofetch to .bashrc")?;
+        .context("Failed to install Doppler")?;
+        runner
+            .run("bash", &["-c", "doppler login"])
+            .context("Failed to log in to Doppler")?;
     }
     Ok(())
 }
 
-fn enable_doppler(enabled: bool) -> Result<(), Error> {
-    if enabled {
-        run_command(
-            "bash",
-            &[
-                "-c",
-                "(curl -Ls --tlsv1.2 --proto \"=https\" --retry 3 https://cli.doppler.com/install.sh || wget -t 3 -qO- https://cli.doppler.com/install.sh) | sudo sh",
-            ],
+fn install_shell(runner: &dyn CommandRunner, config: &ShellConfig) -> Result<(), Error> {
+    let shell = config.name().unwrap_or_else(Shell::detect);
+    let path = Path::new("/usr/bin").join(shell.name());
+    if shell != Shell::Bash && !path.exists() {
+        runner
+            .run_network("sudo", &["apt-get", "install", "-y", shell.name()])
+            .context(format!("Failed to install {shell}"))?;
+    }
+    if config.chsh() {
+        runner
+            .run(
+                "bash",
+                &["-c", &format!("sudo chsh -s {} \"$USER\"", path.display())],
+            )
+            .context(format!("Failed to make {shell} the login shell"))?;
+    }
+    Ok(())
+}
+
+const OH_MY_POSH_INSTALLER: &str = "https://ohmyposh.dev/install.sh";
+
+fn setup_oh_my_posh(
+    runner: &dyn CommandRunner,
+    config: &OhMyPosh,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
+    let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
+    if !home.join(".local/bin/oh-my-posh").exists() && !is_on_path("oh-my-posh") {
+        let bin = home.join(".local/bin");
+        run_script(
+            runner,
+            "oh-my-posh",
+            OH_MY_POSH_INSTALLER,
+            sha256,
+            &["bash"],
+            &["-d", &bin.to_string_lossy()],
         )
-        .context("Failed to install Doppler")?;
-        run_command("bash", &["-c", "doppler login"]).context("Failed to log in to Doppler")?;
+        .context("Failed to install oh-my-posh")?;
+    }
+
+    let config_file = expand_home(&home, &config.config_file());
+    if config.config_path.is_some() {
+        if !config_file.exists() {
+            return Err(Error::msg(format!(
+                "Oh My Posh configuration file {} does not exist",
+                config_file.display()
+            )));
+        }
+    } else if let Some(url) = &config.config_url {
+        runner
+            .run_network(
+                "curl",
+                &[
+                    "-fsSL",
+                    "--create-dirs",
+                    "-o",
+                    &config_file.to_string_lossy(),
+                    url,
+                ],
+            )
+            .context(format!("Failed to download Oh My Posh configuration {url}"))?;
+    } else {
+        find_theme(&home.join(OhMyPosh::THEMES_DIR), config.theme())?;
+    }
+    Ok(())
+}
+
+const STARSHIP_INSTALLER: &str = "https://starship.rs/install.sh";
+
+fn setup_starship(
+    runner: &dyn CommandRunner,
+    config: &PromptConfig,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
+    let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
+    if !home.join(".local/bin/starship").exists() && !is_on_path("starship") {
+        let bin = home.join(".local/bin");
+        run_script(
+            runner,
+            "starship",
+            STARSHIP_INSTALLER,
+            sha256,
+            &["sh"],
+            &["-y", "-b", &bin.to_string_lossy()],
+        )
+        .context("Failed to install starship")?;
+    }
+
+    let config_file = home.join(PromptConfig::STARSHIP_CONFIG);
+    if let Some(preset) = &config.preset {
+        runner
+            .run(
+                "bash",
+                &[
+                    "-c",
+                    &format!(
+                        "mkdir -p ~/.oh-my-droid && starship preset {preset} -o ~/{}",
+                        PromptConfig::STARSHIP_CONFIG
+                    ),
+                ],
+            )
+            .context(format!("Failed to write the starship preset {preset}"))?;
+    } else if let Some(table) = &config.config {
+        let contents =
+            toml::to_string(table).context("Failed to serialize the starship configuration")?;
+        if let Some(parent) = config_file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&config_file, contents)
+            .context(format!("Failed to write {}", config_file.display()))?;
     }
     Ok(())
 }
 
-fn setup_npm(map: &HashMap<String, String>) -> Result<(), Error> {
+/// Checks that `theme` is one of the themes installed in `dir`, suggesting
+/// similarly named ones otherwise.
+fn find_theme(dir: &Path, theme: &str) -> Result<PathBuf, Error> {
+    let path = dir.join(format!("{theme}.omp.json"));
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let mut themes = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    name.strip_suffix(".omp.json").map(String::from)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if themes.is_empty() {
+        return Err(Error::msg(format!(
+            "No Oh My Posh themes found in {}",
+            dir.display()
+        )));
+    }
+
+    themes.sort();
+    let query = theme.to_lowercase();
+    let similar = themes
+        .iter()
+        .filter(|name| name.contains(&query) || query.contains(name.as_str()))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let hint = match similar.is_empty() {
+        true => format!("see {} for the installed themes", dir.display()),
+        false => format!("did you mean {}?", similar.join(", ")),
+    };
+    Err(Error::msg(format!(
+        "Unknown Oh My Posh theme {theme}, {hint}"
+    )))
+}
+
+/// Resolves a leading `~/` of a configured path to `home`.
+pub fn expand_home(home: &Path, path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+fn is_on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).exists()))
+}
+
+/// Puts the node installed by mise, if any, on the PATH of a bash command.
+pub const NODE_ENV: &str = "command -v mise >/dev/null 2>&1 && eval \"$(mise env -s bash)\"";
+
+fn setup_npm(runner: &dyn CommandRunner, map: &HashMap<String, String>) -> Result<(), Error> {
     for (package, version) in map {
-        run_command(
+        runner.run_network(
             "bash",
             &[
                 "-c",
-                &format!("source ~/.bashrc && npm install -g {}@{}", package, version),
+                &format!("{NODE_ENV}; npm install -g {}@{}", package, version),
             ],
         )?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::command::RecordingRunner;
+
+    use super::*;
+
+    #[test]
+    fn test_install_pkgx_commands() {
+        let installed = "/usr/local/pkgs/github.com/junegunn/fzf/v0.55.0\n/usr/local/pkgs/jqlang.github.io/jq/v1.7.1\n/usr/local/pkgs/neovim.io/v0.10.2\n/usr/local/pkgs/crates.io/eza/v0.20.0\n";
+        let outdated = "github.com/junegunn/fzf  0.55.0  0.56.3\n";
+        let runner = RecordingRunner::new()
+            .with_output("pkgm ls", installed)
+            .with_output("pkgm outdated", outdated);
+        let map = HashMap::from([
+            ("rg".to_string(), "latest".to_string()),
+            ("fzf".to_string(), "latest".to_string()),
+            ("eza".to_string(), "latest".to_string()),
+            ("jq".to_string(), "1.6".to_string()),
+            ("neovim.io".to_string(), "0.10".to_string()),
+        ]);
+        install_pkgx_with(&runner, &map, None, true).unwrap();
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "pkgm ls",
+                "pkgm outdated",
+                "pkgm install fzf@0.56.3",
+                "pkgm install jq@1.6",
+                "pkgm install rg",
+            ]
+        );
+
+        // pinned versions only, nothing to check for upgrades
+        let runner = RecordingRunner::new().with_output("pkgm ls", installed);
+        let map = HashMap::from([("jq".to_string(), "1.7".to_string())]);
+        install_pkgx_with(&runner, &map, None, true).unwrap();
+        assert_eq!(runner.command_lines(), ["pkgm ls"]);
+        assert!(!pkgx_version_matches("1.10.0", "1.1"));
+    }
+
+    #[test]
+    fn test_install_pkgx_bootstraps_pkgm() {
+        let runner = RecordingRunner::new();
+        let map = HashMap::from([("jq".to_string(), "latest".to_string())]);
+        install_pkgx_with(&runner, &map, None, false).unwrap();
+        let lines = runner.command_lines();
+        let script = downloaded_file(&lines[0]);
+        assert_eq!(
+            lines,
+            [
+                format!("curl -fsSL -o {script} https://pkgx.sh"),
+                format!("sh {script}"),
+                "pkgm ls".to_string(),
+                "pkgm outdated".to_string(),
+                "pkgm install jq".to_string(),
+            ]
+        );
+
+        let runner = RecordingRunner::new();
+        install_pkgx_with(&runner, &HashMap::new(), None, false).unwrap();
+        assert!(runner.command_lines().is_empty());
+    }
+
+    #[test]
+    fn test_parse_pkgm_list() {
+        assert!(parse_pkgm_list("").is_empty());
+        assert_eq!(
+            parse_pkgm_list(
+                "no packages installed\n/usr/local/bin/fzf\n/usr/local/pkgs/jq\n/usr/local/pkgs/neovim.io/nightly\n  /usr/local/pkgs/github.com/junegunn/fzf/v0.55.0/\n/home/me/.local/pkgs/neovim.io/v0.10.2\n"
+            ),
+            [
+                ("github.com/junegunn/fzf".to_string(), "0.55.0".to_string()),
+                ("neovim.io".to_string(), "0.10.2".to_string()),
+            ]
+        );
+
+        assert!(pkgx_project_matches("github.com/junegunn/fzf", "fzf"));
+        assert!(pkgx_project_matches(
+            "github.com/junegunn/fzf",
+            "github.com/junegunn/fzf"
+        ));
+        assert!(pkgx_project_matches("neovim.io", "neovim.io"));
+        assert!(!pkgx_project_matches("github.com/junegunn/fzf", "junegunn"));
+        assert!(!pkgx_project_matches("github.com/junegunn/fzf", "zf"));
+        assert!(!pkgx_project_matches("neovim.io", "neovim"));
+    }
+
+    #[test]
+    fn test_parse_pkgm_outdated() {
+        assert!(parse_pkgm_outdated("").is_empty());
+        assert_eq!(
+            parse_pkgm_outdated(
+                "project  installed  latest\ngithub.com/junegunn/fzf  v0.55.0  v0.56.3\nneovim.io: 0.10.2 -> 0.11.0\njqlang.github.io/jq  1.7.1  1.7.1\n"
+            ),
+            [
+                ("github.com/junegunn/fzf".to_string(), "0.56.3".to_string()),
+                ("neovim.io".to_string(), "0.11.0".to_string()),
+            ]
+        );
+    }
+
+    /// The file downloaded by a `curl -fsSL -o <file> <url>` command line.
+    fn downloaded_file(command_line: &str) -> String {
+        command_line.split(' ').nth(3).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_private_dir() {
+        let dir = PrivateDir::new("test/dir").unwrap();
+        let other = PrivateDir::new("test/dir").unwrap();
+        assert_ne!(dir.path(), other.path());
+        let metadata = std::fs::metadata(dir.path()).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+
+        let path = dir.path().to_path_buf();
+        std::fs::write(path.join("script.sh"), "echo").unwrap();
+        drop(dir);
+        assert!(!path.exists());
+        assert!(other.path().exists());
+    }
+
+    #[test]
+    fn test_run_curl_installers_commands() {
+        let runner = RecordingRunner::new();
+        let map = HashMap::from([("bun".to_string(), "https://bun.sh/install".into())]);
+        SetupStep::Curl(&map).run(&runner, &HashMap::new()).unwrap();
+        let lines = runner.command_lines();
+        let script = downloaded_file(&lines[0]);
+        assert_eq!(
+            lines,
+            vec![
+                format!("curl -fsSL -o {script} https://bun.sh/install"),
+                format!("bash {script}"),
+            ]
+        );
+        // downloaded to a private directory, removed once the script ran
+        let dir = Path::new(&script).parent().unwrap();
+        assert!(dir.starts_with(std::env::temp_dir()));
+        assert!(script.ends_with("/bun.sh"));
+        assert!(!dir.exists());
+
+        let sha256 = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d";
+        let mut installer = CurlInstaller::from("https://bun.sh/install");
+        installer.pin(sha256);
+        let map = HashMap::from([("bun".to_string(), installer)]);
+        let runner = RecordingRunner::new()
+            .with_program_output("sha256sum", &format!("{}  -\n", sha256.to_uppercase()));
+        SetupStep::Curl(&map).run(&runner, &HashMap::new()).unwrap();
+        let lines = runner.command_lines();
+        let script = downloaded_file(&lines[0]);
+        assert_eq!(lines[1], format!("sha256sum {script}"));
+        assert_eq!(lines[2], format!("bash {script}"));
+
+        let runner = RecordingRunner::new()
+            .with_program_output("sha256sum", &format!("{}  -\n", "0".repeat(64)));
+        let err = SetupStep::Curl(&map)
+            .run(&runner, &HashMap::new())
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Checksum mismatch for https://bun.sh/install"));
+        assert_eq!(runner.command_lines().len(), 2);
+    }
+
+    #[test]
+    fn test_run_curl_installer_options() {
+        let cfg: Configuration = toml::from_str(
+            r#"
+            [curl.rustup]
+            url = "https://sh.rustup.rs"
+            args = ["-y", "--default-toolchain", "stable"]
+            env = { RUSTUP_INIT_SKIP_PATH_CHECK = "yes" }
+            interpreter = "sh"
+
+            [curl.tool]
+            url = "https://example.com/install.sh"
+            env = { PREFIX = "/opt/tool" }
+            sudo = true
+
+            [curl.present]
+            url = "https://example.com/present.sh"
+            creates = "/"
+            "#,
+        )
+        .unwrap();
+        let runner = RecordingRunner::new();
+        SetupStep::Curl(cfg.curl.as_ref().unwrap())
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        let lines = runner.command_lines();
+        let (rustup, tool) = (downloaded_file(&lines[0]), downloaded_file(&lines[2]));
+        assert_eq!(
+            lines,
+            vec![
+                format!("curl -fsSL -o {rustup} https://sh.rustup.rs"),
+                format!("sh {rustup} -y --default-toolchain stable"),
+                format!("curl -fsSL -o {tool} https://example.com/install.sh"),
+                format!("sudo --preserve-env=PREFIX bash {tool}"),
+            ]
+        );
+        let invocations = runner.invocations();
+        // the installer's environment is not set for the download
+        for download in [&invocations[0], &invocations[2]] {
+            assert!(
+                download
+                    .env
+                    .iter()
+                    .all(|(key, _)| key != "RUSTUP_INIT_SKIP_PATH_CHECK" && key != "PREFIX")
+            );
+        }
+        assert!(
+            invocations[1]
+                .env
+                .contains(&("RUSTUP_INIT_SKIP_PATH_CHECK".into(), "yes".into()))
+        );
+        assert!(
+            invocations[3]
+                .env
+                .contains(&("PREFIX".into(), "/opt/tool".into()))
+        );
+        // only the download is retried, never the installer itself
+        let network = invocations
+            .iter()
+            .map(|invocation| invocation.network)
+            .collect::<Vec<_>>();
+        assert_eq!(network, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_setup_npm_commands() {
+        let runner = RecordingRunner::new();
+        let map = HashMap::from([("pnpm".to_string(), "9".to_string())]);
+        SetupStep::Npm(&map).run(&runner, &HashMap::new()).unwrap();
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!("bash -c {NODE_ENV}; npm install -g pnpm@9")]
+        );
+    }
+
+    #[test]
+    fn test_setup_zoxide_skips_installed_version() {
+        let pinned = |version: &str| ZoxideConfig::Options {
+            version: Some(version.into()),
+            cmd: None,
+            hook: None,
+        };
+
+        let runner = RecordingRunner::new().with_output("zoxide --version", "zoxide v0.9.8\n");
+        SetupStep::Zoxide(&pinned("v0.9.8"))
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        SetupStep::Zoxide(&ZoxideConfig::Enabled(true))
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        let lines = runner.command_lines();
+        assert_eq!(lines, ["zoxide --version", "zoxide --version"]);
+
+        let runner = RecordingRunner::new();
+        SetupStep::Zoxide(&ZoxideConfig::Enabled(true))
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        let lines = runner.command_lines();
+        assert!(lines[1].starts_with("curl -fsSL -o "));
+        assert!(
+            lines[1]
+                .ends_with(" https://raw.githubusercontent.com/ajeetdsouza/zoxide/main/install.sh")
+        );
+        assert!(lines[2].starts_with("bash "));
+    }
+
+    #[test]
+    fn test_setup_zoxide_verifies_pinned_release() {
+        let sha256 = "9c1b1ed5b4ae5c05d8cfce2d1d4d4b8f2d2a1f0e8f1a7c6b5d4e3f2a1b0c9d8e";
+        let name = format!(
+            "zoxide-0.9.7-{}-unknown-linux-musl.tar.gz",
+            std::env::consts::ARCH
+        );
+        let release = format!(
+            r#"{{"tag_name": "v0.9.7", "body": null, "author": {{"login": "ajeetdsouza"}}, "assets": [
+                {{"name": "zoxide_0.9.7-1_amd64.deb", "browser_download_url": "https://example.com/zoxide.deb", "digest": null}},
+                {{"name": "{name}", "size": 1234, "browser_download_url": "https://github.com/ajeetdsouza/zoxide/releases/download/v0.9.7/{name}", "digest": "sha256:{sha256}"}}
+            ]}}"#
+        );
+        let release_url =
+            "curl -fsSL https://api.github.com/repos/ajeetdsouza/zoxide/releases/tags/v0.9.7";
+        let config = ZoxideConfig::Options {
+            version: Some("0.9.7".into()),
+            cmd: None,
+            hook: None,
+        };
+
+        let runner = RecordingRunner::new()
+            .with_output("zoxide --version", "zoxide v0.9.8\n")
+            .with_output(release_url, &release)
+            .with_program_output("sha256sum", &format!("{sha256}  -\n"));
+        SetupStep::Zoxide(&config)
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        let lines = runner.command_lines();
+        let tarball = downloaded_file(&lines[2]);
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            lines,
+            [
+                "zoxide --version".to_string(),
+                release_url.to_string(),
+                format!(
+                    "curl -fsSL -o {tarball} https://github.com/ajeetdsouza/zoxide/releases/download/v0.9.7/{name}"
+                ),
+                format!("sha256sum {tarball}"),
+                format!("tar -xzf {tarball} -C {}/.local/bin zoxide", home.display()),
+            ]
+        );
+        assert!(tarball.ends_with(&format!("/{name}")));
+
+        let runner = RecordingRunner::new()
+            .with_output(release_url, &release)
+            .with_program_output("sha256sum", &format!("{}  -\n", "0".repeat(64)));
+        let err = SetupStep::Zoxide(&config)
+            .run(&runner, &HashMap::new())
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Checksum mismatch"));
+        assert!(
+            runner
+                .command_lines()
+                .iter()
+                .all(|line| !line.starts_with("tar "))
+        );
+
+        let runner = RecordingRunner::new().with_output(
+            release_url,
+            &release.replace(&format!("sha256:{sha256}"), ""),
+        );
+        let err = SetupStep::Zoxide(&config)
+            .run(&runner, &HashMap::new())
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("No sha256 published"));
+    }
+
+    #[test]
+    fn test_setup_apt_repository_checks_fingerprint() {
+        let apt_dir = std::env::temp_dir().join(format!("omd-apt-{}", std::process::id()));
+        let (name, repository) = AptRepository::defaults().remove(1);
+        let keys = "pub:-:4096:1:B8B81C9D17413A06:1704067200:::-:::scESC::::::23::0:\nfpr:::::::::24853EC9F655CE80B48E6C3A8B81C9D17413A06D:\n";
+
+        let runner = RecordingRunner::new().with_program_output("gpg", keys);
+        setup_apt_repository(&runner, &name, &repository, &apt_dir).unwrap();
+        let lines = runner.command_lines();
+        let key = downloaded_file(&lines[0]);
+        let work_dir = Path::new(&key).parent().unwrap();
+        assert_eq!(
+            lines[0],
+            format!("curl -fsSL -o {key} https://mise.jdx.dev/gpg-key.pub")
+        );
+        assert_eq!(lines[1], format!("gpg --show-keys --with-colons {key}"));
+        assert!(lines[3].ends_with("--export 24853EC9F655CE80B48E6C3A8B81C9D17413A06D"));
+        assert!(lines[4].ends_with(&format!("{}/keyrings/mise.gpg", apt_dir.display())));
+        assert!(lines[5].ends_with(&format!(
+            "{}/sources.list.d/mise.sources",
+            apt_dir.display()
+        )));
+        assert!(!work_dir.exists());
+
+        let other = keys.replace("24853EC9", "DEADBEEF");
+        let runner = RecordingRunner::new().with_program_output("gpg", &other);
+        let err = setup_apt_repository(&runner, &name, &repository, &apt_dir).unwrap_err();
+        assert!(format!("{err:#}").contains("expected 24853EC9F655CE80B48E6C3A8B81C9D17413A06D"));
+        assert_eq!(runner.command_lines().len(), 2);
+    }
+
+    #[test]
+    fn test_outdated_apt_repositories() {
+        let apt_dir = std::env::temp_dir().join(format!("omd-apt-dir-{}", std::process::id()));
+        std::fs::create_dir_all(apt_dir.join("keyrings")).unwrap();
+        std::fs::create_dir_all(apt_dir.join("sources.list.d")).unwrap();
+        let repositories = AptRepository::defaults();
+        assert_eq!(outdated_apt_repositories(&repositories, &apt_dir).len(), 2);
+
+        let (_, mise) = &repositories[1];
+        let keyring = apt_dir.join("keyrings/mise.gpg");
+        std::fs::write(&keyring, "").unwrap();
+        std::fs::write(
+            apt_dir.join("sources.list.d/mise.sources"),
+            mise.sources(&keyring.to_string_lossy()),
+        )
+        .unwrap();
+        let outdated = outdated_apt_repositories(&repositories, &apt_dir);
+        assert_eq!(outdated.len(), 1);
+        assert_eq!(outdated[0].0, "vscode");
+
+        std::fs::remove_dir_all(&apt_dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_apt_pins_and_holds() {
+        let apt_dir = std::env::temp_dir().join(format!("omd-apt-pins-{}", std::process::id()));
+        let cfg: Configuration = toml::from_str(
+            r#"
+            apt-get = [
+                "git",
+                { name = "tmux", version = "3.4-1", hold = true },
+                { name = "neovim", no-install-recommends = true },
+            ]
+
+            [apt.repositories]
+            vscode = false
+            mise = false
+            "#,
+        )
+        .unwrap();
+
+        let runner = RecordingRunner::new()
+            .with_output(&format!("dpkg-query -W {DPKG_QUERY_FORMAT}"), "")
+            .with_output("apt-mark showhold", "git\nneovim\n");
+        // git was held by oh-my-droid, neovim by hand
+        install_apt_in(&runner, &cfg, &apt_dir, &["git".to_string()]).unwrap();
+        let lines = runner.command_lines();
+        assert!(lines[0].starts_with("sudo install -D -o root -g root -m 644 "));
+        assert!(lines[0].ends_with("/preferences.d/oh-my-droid.pref"));
+        assert_eq!(
+            lines[2..],
+            [
+                "sudo apt-get update",
+                "sudo apt-get install -y git tmux",
+                "sudo apt-get install -y --no-install-recommends neovim",
+                "apt-mark showhold",
+                "sudo apt-mark hold tmux",
+                "sudo apt-mark unhold git",
+            ]
+        );
+
+        // an up to date preferences file is left alone, a stale one removed
+        std::fs::create_dir_all(apt_dir.join("preferences.d")).unwrap();
+        let pref = apt_dir.join("preferences.d/oh-my-droid.pref");
+        std::fs::write(&pref, cfg.apt_preferences().unwrap()).unwrap();
+        let runner = RecordingRunner::new();
+        update_apt_preferences(&runner, cfg.apt_preferences(), &apt_dir).unwrap();
+        assert!(runner.command_lines().is_empty());
+        update_apt_preferences(&runner, None, &apt_dir).unwrap();
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!("sudo rm -f {}", pref.display())]
+        );
+
+        std::fs::remove_dir_all(&apt_dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_apt_skips_installed_packages() {
+        let cfg: Configuration = toml::from_str(
+            r#"
+            apt-get = ["git", "curl", { name = "tmux", version = "3.4-1" }]
+
+            [apt.repositories]
+            vscode = false
+            mise = false
+            "#,
+        )
+        .unwrap();
+        let dpkg_query = format!("dpkg-query -W {DPKG_QUERY_FORMAT}");
+        let apt_dir = std::env::temp_dir().join(format!("omd-apt-dpkg-{}", std::process::id()));
+
+        let installed = "ii  git 1:2.39.5-0+deb12u2\nrc  curl 7.88.1-10\nii  tmux 3.3a-3\n";
+        let runner = RecordingRunner::new()
+            .with_output(&dpkg_query, installed)
+            .with_output("apt-mark showhold", "");
+        let packages = installed_apt_packages(&runner).unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages["git"], "1:2.39.5-0+deb12u2");
+        let plan = SetupStep::AptGet(&cfg).format_dry_run(&runner);
+        assert!(plan.contains("  - git \u{1b}[3m(already installed)"));
+        assert!(plan.contains("(3.3a-3 installed)"));
+
+        let runner = RecordingRunner::new()
+            .with_output(&dpkg_query, installed)
+            .with_output("apt-mark showhold", "");
+        install_apt_in(&runner, &cfg, &apt_dir, &[]).unwrap();
+        assert_eq!(
+            runner.command_lines()[2..],
+            [
+                "sudo apt-get update",
+                "sudo apt-get install -y curl tmux",
+                "apt-mark showhold",
+            ]
+        );
+
+        // nothing to install, not even an apt-get update
+        let installed = "ii  git 1:2.39.5-0+deb12u2\nii  curl 7.88.1-10\nhi  tmux 3.4-1\n";
+        let runner = RecordingRunner::new()
+            .with_output(&dpkg_query, installed)
+            .with_output("apt-mark showhold", "");
+        install_apt_in(&runner, &cfg, &apt_dir, &[]).unwrap();
+        assert_eq!(
+            runner.command_lines()[1..],
+            [dpkg_query.as_str(), "apt-mark showhold"]
+        );
+    }
+
+    const NIX_PROFILE: &str = "Name:               ripgrep
+Flake attribute:    legacyPackages.x86_64-linux.ripgrep
+Original flake URL: flake:nixpkgs
+Locked flake URL:   github:NixOS/nixpkgs/5633bcff0c6162b9e4b5f1264264611e950c8ec7
+Store paths:        /nix/store/2x0ay0zy2cvq6fkpkw2ckz1d6fd1xdl1-ripgrep-14.1.1
+
+Name:               hello
+Flake attribute:    packages.x86_64-linux.default
+Original flake URL: github:example/hello
+Locked flake URL:   github:example/hello/0e1f6f4b3e9dfbc3a4ba1b1ba2cb9b27f07c3bd1
+Store paths:        /nix/store/y1w5hq3ixbnkjq3zxb6c3sbbfs7ab3hz-hello-2.12.1
+";
+
+    #[test]
+    fn test_parse_nix_profile() {
+        let profile = parse_nix_profile(NIX_PROFILE);
+        assert_eq!(profile.len(), 2);
+        assert_eq!(profile[0].name, "ripgrep");
+        assert!(profile[0].provides("nixpkgs#ripgrep"));
+        assert!(!profile[0].provides("nixpkgs#grep"));
+        assert!(!profile[0].provides("github:NixOS/nixpkgs/nixos-24.05#ripgrep"));
+        assert!(profile[1].provides("github:example/hello"));
+        assert!(profile[1].provides("github:example/hello#default"));
+
+        let runner = RecordingRunner::new().with_output("nix profile list", NIX_PROFILE);
+        let map = HashMap::from([
+            ("ripgrep".to_string(), "latest".to_string()),
+            ("fd".to_string(), "latest".to_string()),
+        ]);
+        assert_eq!(
+            nix_store_paths(&runner, &map).unwrap(),
+            HashMap::from([(
+                "ripgrep".to_string(),
+                vec!["/nix/store/2x0ay0zy2cvq6fkpkw2ckz1d6fd1xdl1-ripgrep-14.1.1".to_string()]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_setup_nix_commands() {
+        let runner = RecordingRunner::new().with_output("nix profile list", NIX_PROFILE);
+        let map = HashMap::from([
+            ("fd".to_string(), "latest".to_string()),
+            ("hello".to_string(), "github:example/hello".to_string()),
+            (
+                "ripgrep".to_string(),
+                "github:NixOS/nixpkgs/nixos-24.05#ripgrep".to_string(),
+            ),
+        ]);
+        SetupStep::Nix(&map).run(&runner, &HashMap::new()).unwrap();
+        // nix itself is only installed when missing
+        let lines = runner.command_lines();
+        let listed = lines
+            .iter()
+            .position(|line| line == "nix profile list")
+            .unwrap();
+        assert_eq!(
+            lines[listed..],
+            [
+                "nix profile list",
+                "nix profile install nixpkgs#fd",
+                "nix profile remove ripgrep",
+                "nix profile install github:NixOS/nixpkgs/nixos-24.05#ripgrep",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_version_matches_pin() {
+        assert!(version_matches_pin("20.18.1-1nodesource1", "20.*"));
+        assert!(version_matches_pin("3.4-1", "3.4-1"));
+        assert!(version_matches_pin("1:2.39.5-0+deb12u2", "1:2.*-0+deb12*"));
+        assert!(version_matches_pin("3.4-1", "*"));
+        assert!(!version_matches_pin("3.4-1", "3.4"));
+        assert!(!version_matches_pin("21.0.0", "20.*"));
+    }
+
+    #[test]
+    fn test_setup_home_manager() {
+        let runner = RecordingRunner::new().with_output("nix profile list", NIX_PROFILE);
+        let map = HashMap::from([
+            ("ripgrep".to_string(), "latest".to_string()),
+            (
+                HOME_MANAGER.to_string(),
+                "tangled:@org.dev/home#phone".to_string(),
+            ),
+        ]);
+        SetupStep::Nix(&map).run(&runner, &HashMap::new()).unwrap();
+        let lines = runner.command_lines();
+        let listed = lines
+            .iter()
+            .position(|line| line == "nix profile list")
+            .unwrap();
+        assert_eq!(lines.len(), listed + 2);
+        assert!(
+            lines[listed + 1]
+                .ends_with(" switch --flake git+https://tangled.sh/@org.dev/home#phone")
+        );
+        assert!(
+            !nix_store_paths(&runner, &map)
+                .unwrap()
+                .contains_key(HOME_MANAGER)
+        );
+    }
+
+    #[test]
+    fn test_find_theme() {
+        let dir = std::env::temp_dir().join(format!("omd-themes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for theme in ["atomic", "tokyonight_storm", "tokyo"] {
+            std::fs::write(dir.join(format!("{theme}.omp.json")), "{}").unwrap();
+        }
+
+        assert_eq!(
+            find_theme(&dir, "atomic").unwrap(),
+            dir.join("atomic.omp.json")
+        );
+        let err = find_theme(&dir, "tokyonight").unwrap_err().to_string();
+        assert!(
+            err.ends_with("did you mean tokyo, tokyonight_storm?"),
+            "{err}"
+        );
+        let err = find_theme(&dir, "dracula").unwrap_err().to_string();
+        assert!(err.contains("see "), "{err}");
+        let err = find_theme(&dir.join("missing"), "atomic").unwrap_err();
+        assert!(err.to_string().starts_with("No Oh My Posh themes found"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sort_steps_orders_and_dedupes() {
+        let npm = HashMap::from([("pnpm".to_string(), "latest".to_string())]);
+        let mise = HashMap::from([("node".to_string(), "latest".to_string())]);
+        let cfg = Configuration {
+            apt_get: Some(vec!["stow".into()]),
+            ..Configuration::empty()
+        };
+        let steps = vec![
+            SetupStep::Npm(&npm),
+            SetupStep::Stow(&npm),
+            SetupStep::Mise(&mise),
+            SetupStep::Npm(&npm),
+            SetupStep::AptGet(&cfg),
+            SetupStep::Paths,
+        ];
+        let names = sort_steps(steps)
+            .unwrap()
+            .iter()
+            .map(|step| step.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["paths", "apt-get", "stow", "mise", "npm"]);
+    }
+
+    #[test]
+    fn test_builtin_installers_are_verified() {
+        let sha256 = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d";
+        let installers = HashMap::from([("tailscale".to_string(), sha256.to_string())]);
+        let runner =
+            RecordingRunner::new().with_program_output("sha256sum", &format!("{sha256}  -\n"));
+        SetupStep::Tailscale(true)
+            .run(&runner, &installers)
+            .unwrap();
+        let lines = runner.command_lines();
+        let script = downloaded_file(&lines[0]);
+        assert_eq!(
+            lines,
+            vec![
+                format!("curl -fsSL -o {script} https://tailscale.com/install.sh"),
+                format!("sha256sum {script}"),
+                format!("sh {script}"),
+                "bash -c sudo tailscale up".to_string(),
+                "bash -c sudo tailscale ip".to_string(),
+            ]
+        );
+
+        let runner = RecordingRunner::new()
+            .with_program_output("sha256sum", &format!("{}  -\n", "0".repeat(64)));
+        let err = SetupStep::Tailscale(true)
+            .run(&runner, &installers)
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Checksum mismatch for https://tailscale.com/install.sh")
+        );
+        assert_eq!(runner.command_lines().len(), 2);
+    }
+
+    #[test]
+    fn test_disabled_steps_run_nothing() {
+        let runner = RecordingRunner::new();
+        SetupStep::Zoxide(&ZoxideConfig::Enabled(false))
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        SetupStep::Tailscale(false)
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        SetupStep::Doppler(false)
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        SetupStep::BleSh(&BleShConfig::Enabled(false))
+            .run(&runner, &HashMap::new())
+            .unwrap();
+        assert!(runner.invocations().is_empty());
+    }
+}
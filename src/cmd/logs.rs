@@ -0,0 +1,32 @@
+use anyhow::Error;
+use owo_colors::OwoColorize;
+
+use crate::logs::{list_logs, logs_dir};
+
+pub fn logs(run: Option<&str>) -> Result<(), Error> {
+    let logs = list_logs(&logs_dir()?)?;
+
+    let Some(run) = run else {
+        if logs.is_empty() {
+            println!("No setup runs recorded yet.");
+            return Ok(());
+        }
+        for log in &logs {
+            let name = log.file_stem().unwrap_or_default().to_string_lossy();
+            println!("{}  {}", name.green(), log.display());
+        }
+        return Ok(());
+    };
+
+    let log = match run {
+        "last" => logs.last(),
+        _ => logs.iter().find(|log| {
+            log.file_stem().is_some_and(|stem| stem == run)
+                || log.file_name().is_some_and(|name| name == run)
+        }),
+    }
+    .ok_or_else(|| anyhow::anyhow!("No log found for run {}", run.green()))?;
+
+    print!("{}", std::fs::read_to_string(log)?);
+    Ok(())
+}
@@ -1,2 +1,5 @@
 pub mod init;
+pub mod logs;
+pub mod pin_installers;
 pub mod setup;
+pub mod shell_init;
@@ -0,0 +1,282 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Error};
+use owo_colors::OwoColorize;
+use toml_edit::{DocumentMut, InlineTable, Item, Value};
+
+use crate::{
+    apply::{BUILTIN_INSTALLERS, PrivateDir, download_script, file_sha256},
+    command::{CommandRunner, SystemRunner},
+    config::Configuration,
+};
+
+/// Records the current sha256 of every `[curl]` installer, and of the
+/// built-in installers the configuration uses, in the configuration file
+/// `config_path`.
+pub fn pin_installers(config_path: &str) -> Result<(), Error> {
+    if !Path::new(config_path).exists() {
+        return Err(anyhow::anyhow!("{} does not exist.", config_path.green()));
+    }
+    let toml_str = fs::read_to_string(config_path)?;
+    let cfg: Configuration = toml::from_str(&toml_str)?;
+
+    let runner = SystemRunner::new();
+    let curl = pin_curl_installers(&runner, &cfg)?;
+    let builtin = pin_builtin_installers(&runner, &cfg)?;
+    if curl.is_empty() && builtin.is_empty() {
+        println!("All installers are already pinned.");
+        return Ok(());
+    }
+    fs::write(config_path, write_checksums(&toml_str, &curl, &builtin)?)?;
+    println!(
+        "Pinned {} installer(s) in {}.",
+        curl.len() + builtin.len(),
+        config_path.green()
+    );
+    Ok(())
+}
+
+/// Returns the name and sha256 of the `[curl]` installers whose script
+/// currently served does not match the pinned one.
+fn pin_curl_installers(
+    runner: &dyn CommandRunner,
+    cfg: &Configuration,
+) -> Result<Vec<(String, String)>, Error> {
+    let installers = cfg
+        .curl
+        .iter()
+        .flatten()
+        .map(|(name, installer)| (name.as_str(), installer.url(), installer.sha256()));
+    pin_scripts(runner, installers)
+}
+
+/// Returns the name and sha256 of the built-in installers used by the
+/// configuration whose script currently served does not match the one
+/// pinned in `[installers]`.
+fn pin_builtin_installers(
+    runner: &dyn CommandRunner,
+    cfg: &Configuration,
+) -> Result<Vec<(String, String)>, Error> {
+    let used = cfg.builtin_installers();
+    let installers = BUILTIN_INSTALLERS
+        .iter()
+        .filter(|(name, _)| used.contains(name))
+        .map(|(name, url)| {
+            let sha256 = cfg.installers.iter().flatten().find(|(n, _)| n == name);
+            (*name, *url, sha256.map(|(_, sha256)| sha256.as_str()))
+        });
+    pin_scripts(runner, installers)
+}
+
+/// Downloads the `(name, url, pinned sha256)` scripts, returning the name
+/// and sha256 of those that changed, sorted by name.
+fn pin_scripts<'a>(
+    runner: &dyn CommandRunner,
+    scripts: impl Iterator<Item = (&'a str, &'a str, Option<&'a str>)>,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut scripts = scripts.collect::<Vec<_>>();
+    scripts.sort_by_key(|(name, _, _)| *name);
+
+    let dir = PrivateDir::new("pin-installers")?;
+    let mut pinned = Vec::new();
+    for (name, url, current) in scripts {
+        let script = download_script(runner, dir.path(), name, url)?;
+        let sha256 = file_sha256(runner, &script)?;
+        if current == Some(sha256.as_str()) {
+            continue;
+        }
+        println!("{} {} {}", name.green(), url, sha256.cyan());
+        pinned.push((name.to_string(), sha256));
+    }
+    Ok(pinned)
+}
+
+/// Sets the sha256 of the `curl` installers in the `[curl]` section of
+/// `toml_str`, and of the `builtin` ones in `[installers]`, leaving the
+/// comments and the rest of the file untouched.
+fn write_checksums(
+    toml_str: &str,
+    curl: &[(String, String)],
+    builtin: &[(String, String)],
+) -> Result<String, Error> {
+    let mut doc = toml_str
+        .parse::<DocumentMut>()
+        .context("Failed to parse configuration")?;
+    if !builtin.is_empty() {
+        let installers = doc
+            .entry("installers")
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .context("Invalid [installers] section in the configuration")?;
+        for (name, sha256) in builtin {
+            installers.insert(name, toml_edit::value(sha256.as_str()));
+        }
+    }
+    if curl.is_empty() {
+        return Ok(doc.to_string());
+    }
+    let curl_section = doc
+        .get_mut("curl")
+        .and_then(Item::as_table_like_mut)
+        .context("No [curl] section in the configuration")?;
+    for (name, sha256) in curl {
+        let entry = curl_section
+            .get_mut(name)
+            .with_context(|| format!("No curl installer named {name}"))?;
+        match entry {
+            Item::Value(Value::String(url)) => {
+                let mut table = InlineTable::new();
+                table.insert("url", Value::from(url.value().as_str()));
+                table.insert("sha256", Value::from(sha256.as_str()));
+                let mut value = Value::InlineTable(table);
+                *value.decor_mut() = url.decor().clone();
+                *entry = Item::Value(value);
+            }
+            _ => {
+                entry
+                    .as_table_like_mut()
+                    .with_context(|| format!("Invalid curl installer {name}"))?
+                    .insert("sha256", toml_edit::value(sha256.as_str()));
+            }
+        }
+    }
+    Ok(doc.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::command::RecordingRunner;
+
+    use super::*;
+
+    #[test]
+    fn test_pin_curl_installers() {
+        let sha256 = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d";
+        let mut cfg = Configuration {
+            curl: Some(HashMap::from([
+                ("bun".to_string(), "https://bun.sh/install".into()),
+                ("deno".to_string(), "https://deno.land/install.sh".into()),
+            ])),
+            ..Configuration::empty()
+        };
+        cfg.curl
+            .as_mut()
+            .unwrap()
+            .get_mut("deno")
+            .unwrap()
+            .pin(sha256);
+
+        let runner =
+            RecordingRunner::new().with_program_output("sha256sum", &format!("{sha256}  -\n"));
+        assert_eq!(
+            pin_curl_installers(&runner, &cfg).unwrap(),
+            vec![("bun".to_string(), sha256.to_string())]
+        );
+        let lines = runner.command_lines();
+        assert!(lines[0].starts_with("curl -fsSL -o "));
+        assert!(lines[0].ends_with("/bun.sh https://bun.sh/install"));
+    }
+
+    #[test]
+    fn test_write_checksums_keeps_the_rest_of_the_file() {
+        let sha256 = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d";
+        let toml_str = r#"# my phone
+apt-get = ["git", "tmux"] # the essentials
+
+[alias]
+ls = "eza -lh"
+
+[curl]
+# the bun runtime
+bun = "https://bun.sh/install" # unpinned
+deno = { url = "https://deno.land/install.sh", sha256 = "00" }
+
+[curl.atuin]
+url = "https://setup.atuin.sh"
+args = ["--non-interactive"]
+
+[mise]
+node = "latest"
+"#;
+        let pinned = ["bun", "deno", "atuin"].map(|name| (name.to_string(), sha256.to_string()));
+        assert_eq!(
+            write_checksums(toml_str, &pinned, &[]).unwrap(),
+            format!(
+                r#"# my phone
+apt-get = ["git", "tmux"] # the essentials
+
+[alias]
+ls = "eza -lh"
+
+[curl]
+# the bun runtime
+bun = {{ url = "https://bun.sh/install", sha256 = "{sha256}" }} # unpinned
+deno = {{ url = "https://deno.land/install.sh", sha256 = "{sha256}" }}
+
+[curl.atuin]
+url = "https://setup.atuin.sh"
+args = ["--non-interactive"]
+sha256 = "{sha256}"
+
+[mise]
+node = "latest"
+"#
+            )
+        );
+        assert!(write_checksums("[alias]\nls = \"eza\"\n", &pinned, &[]).is_err());
+    }
+
+    #[test]
+    fn test_pin_builtin_installers() {
+        let sha256 = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d";
+        let toml_str = r#"tailscale = true # my tailnet
+doppler = false
+
+[nix]
+ripgrep = "latest"
+
+[installers]
+nix = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d"
+"#;
+        let cfg: Configuration = toml::from_str(toml_str).unwrap();
+        let runner =
+            RecordingRunner::new().with_program_output("sha256sum", &format!("{sha256}  -\n"));
+        let pinned = pin_builtin_installers(&runner, &cfg).unwrap();
+        assert_eq!(pinned, vec![("tailscale".to_string(), sha256.to_string())]);
+        let downloads = runner
+            .command_lines()
+            .into_iter()
+            .filter(|line| line.starts_with("curl"))
+            .collect::<Vec<_>>();
+        assert_eq!(downloads.len(), 2);
+        assert!(downloads[0].ends_with("/nix.sh https://install.determinate.systems/nix"));
+        assert!(downloads[1].ends_with("/tailscale.sh https://tailscale.com/install.sh"));
+
+        let written = write_checksums(toml_str, &[], &pinned).unwrap();
+        assert_eq!(
+            written,
+            format!(
+                r#"tailscale = true # my tailnet
+doppler = false
+
+[nix]
+ripgrep = "latest"
+
+[installers]
+nix = "{sha256}"
+tailscale = "{sha256}"
+"#
+            )
+        );
+        let cfg: Configuration = toml::from_str(&written).unwrap();
+        assert!(cfg.validate().is_ok());
+
+        let written = write_checksums("tailscale = true\n", &[], &pinned).unwrap();
+        assert_eq!(
+            written,
+            format!("tailscale = true\n\n[installers]\ntailscale = \"{sha256}\"\n")
+        );
+    }
+}
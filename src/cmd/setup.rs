@@ -1,26 +1,64 @@
-use std::{fs, path::Path};
+use std::{fs, process};
 
 use anyhow::{Context, Error};
 use owo_colors::OwoColorize;
 
 use crate::{
-    command::run_command,
-    config::Configuration,
+    apply::SetupStep,
+    command::{CommandError, CommandRunner, RetryRunner, SystemRunner},
+    config::{ApplyOptions, Configuration},
     consts::CONFIG_FILE,
     diff::compare_configurations,
     git::{extract_repo_name, extract_version},
+    journal::Journal,
+    lock::Lock,
+    logs::RunLog,
 };
 
-pub fn setup(dry_run: bool, no_confirm: bool, config_path: &str) -> Result<(), Error> {
+#[derive(Debug, Clone, Default)]
+pub struct SetupOptions {
+    pub no_confirm: bool,
+    /// Continue the last interrupted run instead of starting a new one.
+    pub resume: bool,
+    pub config_path: String,
+    pub apply: ApplyOptions,
+}
+
+pub fn setup(options: &SetupOptions) -> Result<(), Error> {
     let mut cfg = Configuration::default();
 
-    let repo_url = parse_config_path(config_path)?;
+    let mut journal = match options.resume {
+        true => {
+            let journal = Journal::load()?
+                .ok_or_else(|| anyhow::anyhow!("There is no interrupted setup to resume."))?;
+            println!(
+                "Resuming the setup of {} started at {}",
+                journal.config.green(),
+                journal.started_at
+            );
+            journal
+        }
+        false => Journal::new(&options.config_path),
+    };
+
+    let repo_url = parse_config_path(&journal.config)?;
     let (repo_url, version) = match repo_url.starts_with("https://") {
         true => extract_version(&repo_url),
         false => (repo_url, None),
     };
 
-    let toml_config = match clone_repo(&repo_url, version) {
+    let log = RunLog::create()?;
+    println!(
+        "Logging this run to {}",
+        log.path().display().to_string().green()
+    );
+    let runner = SystemRunner::with_log(log);
+
+    let toml_config = match clone_repo(
+        &RetryRunner::new(&runner, options.apply.retry.policy()),
+        &repo_url,
+        version,
+    ) {
         Ok(toml_config) => toml_config,
         Err(err) => {
             if !repo_url.starts_with("https://") {
@@ -43,17 +81,16 @@ pub fn setup(dry_run: bool, no_confirm: bool, config_path: &str) -> Result<(), E
         ));
     }
 
-    let home_dir =
-        dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Failed to get home directory"))?;
-    let diffs = match Path::new(&home_dir).join(".oh-my-droid/lock.toml").exists() {
-        true => {
-            let old_cfg = Configuration::load_lock_file()?;
-            compare_configurations(&old_cfg, &cfg)
-        }
-        false => compare_configurations(&Configuration::empty(), &cfg),
-    };
+    let fingerprint = cfg.fingerprint()?;
+    if options.resume {
+        journal.check_fingerprint(&fingerprint)?;
+    }
+    journal.fingerprint = fingerprint;
+
+    let diffs = compare_configurations(&Lock::load()?.config, &cfg);
 
     if diffs.is_empty() {
+        Journal::clear()?;
         println!(
             "{}",
             "No changes detected. Your environment is already up to date.".green()
@@ -68,7 +105,14 @@ pub fn setup(dry_run: bool, no_confirm: bool, config_path: &str) -> Result<(), E
         println!("{}", d);
     }
 
-    if !no_confirm && !dry_run {
+    let removals = cfg
+        .diffs_to_setup_steps(&diffs)?
+        .iter()
+        .filter(|step| matches!(step, SetupStep::Remove(_)))
+        .map(|step| step.format_dry_run(&runner))
+        .collect::<Vec<_>>();
+
+    if !options.no_confirm && !options.apply.dry_run {
         match std::path::Path::new(CONFIG_FILE).exists() {
             true => {
                 println!(
@@ -89,28 +133,69 @@ pub fn setup(dry_run: bool, no_confirm: bool, config_path: &str) -> Result<(), E
             println!("Setup cancelled.");
             return Ok(());
         }
+
+        if !removals.is_empty() {
+            println!(
+                "{}",
+                "The following entries were removed from the configuration and will be uninstalled:"
+                    .red()
+                    .bold()
+            );
+            for removal in &removals {
+                println!("{}", removal);
+            }
+            println!("Do you want to remove them? (y/N)");
+
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            if !input.trim().eq_ignore_ascii_case("y") {
+                println!("Setup cancelled.");
+                return Ok(());
+            }
+        }
     }
 
-    cfg.setup_environment(dry_run, diffs)?;
+    cfg.setup_environment(&runner, &options.apply, journal, &diffs)?;
 
     Ok(())
 }
 
-fn parse_config_path(config_path: &str) -> Result<String, Error> {
-    if config_path.starts_with("github:") {
-        let repo = &config_path["github:".len()..];
+/// Reports a failed setup. Failures of external commands exit with the
+/// command's own exit code; any other error is handed back to the caller.
+pub fn report_failure(err: Error) -> Result<(), Error> {
+    let Some(command_err) = err.downcast_ref::<CommandError>() else {
+        return Err(err);
+    };
+
+    eprintln!("{} {:#}", "Setup failed:".red().bold(), err);
+    if !command_err.stderr_tail.is_empty() {
+        eprintln!("Last lines of stderr:");
+        for line in &command_err.stderr_tail {
+            eprintln!("  {}", line.magenta());
+        }
+    }
+
+    process::exit(command_err.exit_code());
+}
+
+/// Expands the `github:` and `tangled:` shorthands to https URLs.
+pub fn parse_config_path(config_path: &str) -> Result<String, Error> {
+    if let Some(repo) = config_path.strip_prefix("github:") {
         return Ok(format!("https://github.com/{}", repo));
     }
 
-    if config_path.starts_with("tangled:") {
-        let repo = &config_path["tangled:".len()..];
+    if let Some(repo) = config_path.strip_prefix("tangled:") {
         return Ok(format!("https://tangled.sh/{}", repo));
     }
 
     Ok(config_path.to_string())
 }
 
-fn clone_repo(repo_url: &str, version: Option<String>) -> Result<String, Error> {
+fn clone_repo(
+    runner: &dyn CommandRunner,
+    repo_url: &str,
+    version: Option<String>,
+) -> Result<String, Error> {
     if !repo_url.starts_with("https://") {
         return Err(anyhow::anyhow!(
             "Unsupported repository URL. Only HTTPS URLs are supported."
@@ -124,31 +209,23 @@ fn clone_repo(repo_url: &str, version: Option<String>) -> Result<String, Error>
     fs::create_dir_all(&cache)?;
     let dest = cache.join(repo_name);
 
-    run_command(
+    runner.run(
         "bash",
         &["-c", "type git || (apt update && apt install -y git)"],
     )?;
 
     match dest.exists() {
         true => {
-            run_command("git", &["-C", dest.to_str().unwrap(), "pull"])?;
+            runner.run_network("git", &["-C", dest.to_str().unwrap(), "pull"])?;
         }
         false => {
-            run_command("git", &["clone", repo_url, dest.to_str().unwrap()])?;
+            runner.run_network("git", &["clone", repo_url, dest.to_str().unwrap()])?;
         }
     }
 
-    if version.is_some() {
-        run_command("git", &["-C", dest.to_str().unwrap(), "fetch", "--all"])?;
-        run_command(
-            "git",
-            &[
-                "-C",
-                dest.to_str().unwrap(),
-                "checkout",
-                version.as_ref().unwrap(),
-            ],
-        )?;
+    if let Some(version) = &version {
+        runner.run_network("git", &["-C", dest.to_str().unwrap(), "fetch", "--all"])?;
+        runner.run("git", &["-C", dest.to_str().unwrap(), "checkout", version])?;
     }
 
     if !dest.join("oh-my-droid.toml").exists() {
@@ -0,0 +1,58 @@
+use anyhow::Error;
+
+use crate::{
+    config::Configuration,
+    lock::Lock,
+    shell::{Shell, render_init_script},
+};
+
+/// Prints the init script for `shell`, or the configured shell, rendered
+/// from the applied configuration in the lock file.
+pub fn shell_init(shell: Option<&str>) -> Result<(), Error> {
+    print!("{}", render_shell_init(&Lock::load()?.config, shell)?);
+    Ok(())
+}
+
+fn render_shell_init(cfg: &Configuration, shell: Option<&str>) -> Result<String, Error> {
+    let shell = match shell {
+        Some(name) => {
+            Shell::from_name(name).ok_or_else(|| anyhow::anyhow!("Unsupported shell: {}", name))?
+        }
+        None => cfg.shell(),
+    };
+    Ok(render_init_script(cfg, shell))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &str = r#"
+shell = "zsh"
+path = ["~/go/bin"]
+nix-store-paths = { ripgrep = ["/nix/store/2x0ay0zy2cvq6fkpkw2ckz1d6fd1xdl1-ripgrep-14.1.1"] }
+
+[alias]
+ls = "eza -lh"
+"#;
+
+    #[test]
+    fn test_render_shell_init_from_lock() {
+        let lock: Lock = toml::from_str(LOCK).unwrap();
+        let script = render_shell_init(&lock.config, None).unwrap();
+        assert_eq!(script, render_init_script(&lock.config, Shell::Zsh));
+        assert!(script.contains("alias ls='eza -lh'"));
+        assert!(script.contains("$HOME/go/bin"));
+    }
+
+    #[test]
+    fn test_render_shell_init_shell_argument() {
+        let lock: Lock = toml::from_str(LOCK).unwrap();
+        let script = render_shell_init(&lock.config, Some("fish")).unwrap();
+        assert_eq!(script, render_init_script(&lock.config, Shell::Fish));
+        assert!(script.contains("alias ls 'eza -lh'"));
+
+        let err = render_shell_init(&lock.config, Some("tcsh")).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported shell: tcsh");
+    }
+}
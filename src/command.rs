@@ -1,49 +1,1020 @@
-use std::process::{self, Command};
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    os::unix::process::ExitStatusExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
 use owo_colors::OwoColorize;
 
-pub fn run_command(cmd: &str, args: &[&str]) -> Result<(), Error> {
-    println!(
-        "{} {} {}",
-        "=>".green(),
-        cmd.green(),
-        args.join(" ").green()
-    );
-    let status = Command::new(cmd)
-        .args(args)
-        .env(
-            "PATH",
-            format!(
-                "{}:{}/.local/bin:{}",
-                "/nix/var/nix/profiles/default/bin",
-                std::env::var("HOME")?,
-                std::env::var("PATH")?
+use crate::logs::RunLog;
+
+/// Number of stderr lines kept in a [`CommandError`].
+const STDERR_TAIL_LINES: usize = 20;
+/// Upper bound on the output bytes buffered while a command runs.
+const OUTPUT_BUFFER_SIZE: usize = 16 * 1024;
+/// How long a timed out command gets to exit after SIGTERM before it is killed.
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// A single process invocation: the program, its arguments and any
+/// environment variables overridden for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    /// Whether the command talks to the network and should be subject to
+    /// the step's [`RetryPolicy`].
+    pub network: bool,
+    pub timeout: Option<Duration>,
+    /// Prefix for each line of output, used to tell apart commands of steps
+    /// running in parallel.
+    pub label: Option<String>,
+    /// Whether the command may prompt the user. Its output then goes
+    /// straight to the terminal, neither labeled nor logged.
+    pub interactive: bool,
+}
+
+impl Invocation {
+    pub fn new(program: &str, args: &[&str]) -> Self {
+        Self {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: Vec::new(),
+            network: false,
+            timeout: None,
+            label: None,
+            interactive: false,
+        }
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn network(mut self) -> Self {
+        self.network = true;
+        self
+    }
+
+    /// Prepends the nix profile and `~/.local/bin` to `PATH`, so binaries
+    /// installed by previous steps can be found.
+    pub fn with_local_path(self) -> Result<Self, Error> {
+        let path = format!(
+            "{}:{}/.local/bin:{}",
+            "/nix/var/nix/profiles/default/bin",
+            std::env::var("HOME")?,
+            std::env::var("PATH")?
+        );
+        Ok(self.env("PATH", &path))
+    }
+
+    /// The command line as it would be typed in a shell, without env overrides.
+    pub fn command_line(&self) -> String {
+        format_command(&self.program, &self.args)
+    }
+}
+
+/// A command that exited with a non-zero status, was killed by a signal or
+/// ran past its timeout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError {
+    pub program: String,
+    pub args: Vec<String>,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: Option<Duration>,
+    pub stderr_tail: Vec<String>,
+}
+
+impl CommandError {
+    /// The exit code oh-my-droid should exit with, following the shell
+    /// conventions of `124` for timeouts and `128 + signal` for killed
+    /// processes.
+    pub fn exit_code(&self) -> i32 {
+        if self.timed_out.is_some() {
+            return 124;
+        }
+        match (self.code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let command = format_command(&self.program, &self.args);
+        if let Some(timeout) = self.timed_out {
+            return write!(f, "`{}` timed out after {}s", command, timeout.as_secs());
+        }
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "`{}` exited with code {}", command, code),
+            (None, Some(signal)) => {
+                write!(f, "`{}` was terminated by signal {}", command, signal)
+            }
+            (None, None) => write!(f, "`{}` failed", command),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+pub trait CommandRunner: Sync {
+    fn execute(&self, invocation: &Invocation) -> Result<(), Error>;
+
+    /// Runs `invocation` without echoing its output, which is returned
+    /// instead. Used to inspect the system, e.g. installed versions.
+    fn capture(&self, invocation: &Invocation) -> Result<String, Error>;
+
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<(), Error> {
+        self.execute(&Invocation::new(cmd, args).with_local_path()?)
+    }
+
+    fn run_without_local_path(&self, cmd: &str, args: &[&str]) -> Result<(), Error> {
+        self.execute(&Invocation::new(cmd, args))
+    }
+
+    /// Like [`CommandRunner::run`], for commands that download something.
+    fn run_network(&self, cmd: &str, args: &[&str]) -> Result<(), Error> {
+        self.execute(&Invocation::new(cmd, args).with_local_path()?.network())
+    }
+
+    /// Like [`CommandRunner::run`], returning the command's standard output.
+    fn output(&self, cmd: &str, args: &[&str]) -> Result<String, Error> {
+        self.capture(&Invocation::new(cmd, args).with_local_path()?)
+    }
+}
+
+/// Timeout and retry-with-backoff settings for network-bound commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+    /// Delay before the first retry, doubled after each attempt.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            retries: 2,
+            backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Applies a [`RetryPolicy`] to the network-bound invocations of another
+/// runner. Other invocations are passed through untouched.
+pub struct RetryRunner<'a> {
+    inner: &'a dyn CommandRunner,
+    policy: RetryPolicy,
+}
+
+impl<'a> RetryRunner<'a> {
+    pub fn new(inner: &'a dyn CommandRunner, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+impl CommandRunner for RetryRunner<'_> {
+    fn execute(&self, invocation: &Invocation) -> Result<(), Error> {
+        if !invocation.network {
+            return self.inner.execute(invocation);
+        }
+
+        let mut invocation = invocation.clone();
+        invocation.timeout = invocation.timeout.or(self.policy.timeout);
+
+        let mut delay = self.policy.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.inner.execute(&invocation) {
+                Err(err)
+                    if attempt < self.policy.retries
+                        && err.downcast_ref::<CommandError>().is_some() =>
+                {
+                    attempt += 1;
+                    println!(
+                        "{} {}, retrying in {}s ({}/{})",
+                        "=>".yellow(),
+                        err,
+                        delay.as_secs(),
+                        attempt,
+                        self.policy.retries
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn capture(&self, invocation: &Invocation) -> Result<String, Error> {
+        self.inner.capture(invocation)
+    }
+}
+
+/// Labels every invocation of another runner, see [`Invocation::label`].
+pub struct LabeledRunner<'a> {
+    inner: &'a dyn CommandRunner,
+    label: String,
+}
+
+impl<'a> LabeledRunner<'a> {
+    pub fn new(inner: &'a dyn CommandRunner, label: &str) -> Self {
+        Self {
+            inner,
+            label: label.to_string(),
+        }
+    }
+}
+
+impl LabeledRunner<'_> {
+    fn labeled(&self, invocation: &Invocation) -> Invocation {
+        let mut invocation = invocation.clone();
+        invocation.label.get_or_insert_with(|| self.label.clone());
+        invocation
+    }
+}
+
+impl CommandRunner for LabeledRunner<'_> {
+    fn execute(&self, invocation: &Invocation) -> Result<(), Error> {
+        self.inner.execute(&self.labeled(invocation))
+    }
+
+    fn capture(&self, invocation: &Invocation) -> Result<String, Error> {
+        self.inner.capture(&self.labeled(invocation))
+    }
+}
+
+/// Marks every invocation of another runner as interactive, see
+/// [`Invocation::interactive`].
+pub struct InteractiveRunner<'a> {
+    inner: &'a dyn CommandRunner,
+}
+
+impl<'a> InteractiveRunner<'a> {
+    pub fn new(inner: &'a dyn CommandRunner) -> Self {
+        Self { inner }
+    }
+}
+
+impl CommandRunner for InteractiveRunner<'_> {
+    fn execute(&self, invocation: &Invocation) -> Result<(), Error> {
+        let mut invocation = invocation.clone();
+        invocation.interactive = true;
+        self.inner.execute(&invocation)
+    }
+
+    fn capture(&self, invocation: &Invocation) -> Result<String, Error> {
+        self.inner.capture(invocation)
+    }
+}
+
+/// Adds the configured environment variables and `PATH` entries to every
+/// invocation of another runner. Values may refer to the environment of
+/// oh-my-droid as `$NAME` or `${NAME}`, and to the home directory as `~`.
+pub struct EnvRunner<'a> {
+    inner: &'a dyn CommandRunner,
+    env: Vec<(String, String)>,
+    path: Vec<String>,
+}
+
+impl<'a> EnvRunner<'a> {
+    pub fn new(
+        inner: &'a dyn CommandRunner,
+        env: Vec<(String, String)>,
+        path: Vec<String>,
+    ) -> Self {
+        Self { inner, env, path }
+    }
+
+    fn expand(value: &str) -> String {
+        let value = match value.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("$HOME{rest}"),
+            _ => value.to_string(),
+        };
+        let mut expanded = String::new();
+        let mut rest = value.as_str();
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let (name, remainder) = match after.strip_prefix('{') {
+                Some(braced) => match braced.find('}') {
+                    Some(end) => (&braced[..end], &braced[end + 1..]),
+                    None => ("", after),
+                },
+                None if after.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+                    let end = after
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+                None => ("", after),
+            };
+            match name.is_empty() {
+                true => expanded.push('$'),
+                false => expanded.push_str(&std::env::var(name).unwrap_or_default()),
+            }
+            rest = remainder;
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+impl CommandRunner for EnvRunner<'_> {
+    fn execute(&self, invocation: &Invocation) -> Result<(), Error> {
+        self.inner.execute(&self.with_env(invocation))
+    }
+
+    fn capture(&self, invocation: &Invocation) -> Result<String, Error> {
+        self.inner.capture(&self.with_env(invocation))
+    }
+}
+
+impl EnvRunner<'_> {
+    fn with_env(&self, invocation: &Invocation) -> Invocation {
+        let mut env = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.clone(), Self::expand(value)))
+            .collect::<Vec<_>>();
+        // variables set by the invocation itself take precedence
+        env.extend(invocation.env.iter().cloned());
+
+        if !self.path.is_empty() {
+            let base = env
+                .iter()
+                .rev()
+                .find(|(key, _)| key == "PATH")
+                .map(|(_, value)| value.clone())
+                .unwrap_or_else(|| std::env::var("PATH").unwrap_or_default());
+            let mut path = self
+                .path
+                .iter()
+                .map(|entry| Self::expand(entry))
+                .collect::<Vec<_>>();
+            path.push(base);
+            env.push(("PATH".to_string(), path.join(":")));
+        }
+
+        let mut invocation = invocation.clone();
+        invocation.env = env;
+        invocation
+    }
+}
+
+/// Runs commands on the host system, optionally recording them in a [`RunLog`].
+#[derive(Debug, Default)]
+pub struct SystemRunner {
+    log: Option<RunLog>,
+}
+
+impl SystemRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_log(log: RunLog) -> Self {
+        Self { log: Some(log) }
+    }
+
+    pub fn log(&self) -> Option<&RunLog> {
+        self.log.as_ref()
+    }
+}
+
+impl CommandRunner for SystemRunner {
+    fn execute(&self, invocation: &Invocation) -> Result<(), Error> {
+        let prefix = invocation
+            .label
+            .as_ref()
+            .map(|label| format!("[{}] ", label));
+        println!(
+            "{}{} {} {}",
+            prefix.as_deref().unwrap_or_default().cyan(),
+            "=>".green(),
+            invocation.program.green(),
+            invocation.args.join(" ").green()
+        );
+        let log = self.log.as_ref();
+        if let Some(log) = log {
+            log.command_started(invocation);
+        }
+
+        // interactive commands keep the terminal, e.g. for apt's progress
+        // bar or login prompts checking isatty
+        let output = || match invocation.interactive {
+            true => Stdio::inherit(),
+            false => Stdio::piped(),
+        };
+        let started = Instant::now();
+        let mut child = match Command::new(&invocation.program)
+            .args(&invocation.args)
+            .envs(invocation.env.iter().map(|(k, v)| (k, v)))
+            .stdout(output())
+            .stderr(output())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => {
+                if let Some(log) = log {
+                    log.command_finished(
+                        invocation,
+                        &format!("failed to start: {}", err),
+                        started.elapsed(),
+                    );
+                }
+                return Err(err.into());
+            }
+        };
+
+        let (status, stderr) = match (child.stdout.take(), child.stderr.take()) {
+            (Some(stdout), Some(stderr)) => thread::scope(|s| {
+                let prefix = prefix.as_deref();
+                s.spawn(move || tee(stdout, std::io::stdout(), log, prefix));
+                let stderr = s.spawn(move || tee(stderr, std::io::stderr(), log, prefix));
+                let status = wait_with_timeout(&mut child, invocation.timeout);
+                (status, stderr.join().unwrap_or_default())
+            }),
+            _ => (
+                wait_with_timeout(&mut child, invocation.timeout),
+                Vec::new(),
             ),
-        )
-        .status()?;
+        };
+        let (status, timed_out) = status?;
+        let timed_out = timed_out.then_some(invocation.timeout).flatten();
+
+        if let Some(log) = log {
+            log.command_finished(invocation, &outcome(status, timed_out), started.elapsed());
+        }
+
+        if !status.success() || timed_out.is_some() {
+            return Err(command_error(invocation, status, timed_out, &stderr).into());
+        }
+
+        Ok(())
+    }
+
+    fn capture(&self, invocation: &Invocation) -> Result<String, Error> {
+        let log = self.log.as_ref();
+        if let Some(log) = log {
+            log.command_started(invocation);
+        }
+
+        let started = Instant::now();
+        let output = Command::new(&invocation.program)
+            .args(&invocation.args)
+            .envs(invocation.env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .output();
+        let output = match output {
+            Ok(output) => output,
+            Err(err) => {
+                if let Some(log) = log {
+                    log.command_finished(
+                        invocation,
+                        &format!("failed to start: {}", err),
+                        started.elapsed(),
+                    );
+                }
+                return Err(err.into());
+            }
+        };
+
+        if let Some(log) = log {
+            log.output(&output.stdout);
+            log.command_finished(invocation, &outcome(output.status, None), started.elapsed());
+        }
+
+        if !output.status.success() {
+            return Err(command_error(invocation, output.status, None, &output.stderr).into());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+fn outcome(status: ExitStatus, timed_out: Option<Duration>) -> String {
+    match (timed_out, status.code(), status.signal()) {
+        (Some(timeout), _, _) => format!("timed out after {}s", timeout.as_secs()),
+        (None, Some(code), _) => format!("exit code {}", code),
+        (None, None, Some(signal)) => format!("terminated by signal {}", signal),
+        (None, None, None) => "unknown exit status".to_string(),
+    }
+}
+
+fn command_error(
+    invocation: &Invocation,
+    status: ExitStatus,
+    timed_out: Option<Duration>,
+    stderr: &[u8],
+) -> CommandError {
+    CommandError {
+        program: invocation.program.clone(),
+        args: invocation.args.clone(),
+        code: status.code(),
+        signal: status.signal(),
+        timed_out,
+        stderr_tail: tail_lines(stderr, STDERR_TAIL_LINES),
+    }
+}
+
+fn format_command(program: &str, args: &[String]) -> String {
+    match args.is_empty() {
+        true => program.to_string(),
+        false => format!("{} {}", program, args.join(" ")),
+    }
+}
+
+/// Waits for `child`, terminating its whole process tree once `timeout`
+/// elapses. Returns the exit status and whether the timeout was hit.
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> io::Result<(ExitStatus, bool)> {
+    let Some(timeout) = timeout else {
+        return Ok((child.wait()?, false));
+    };
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
 
-    if !status.success() {
-        println!("Command failed: {}", status);
-        process::exit(status.code().unwrap_or(1));
+    signal_process_tree(child.id(), "TERM");
+    let grace_deadline = Instant::now() + TERMINATE_GRACE_PERIOD;
+    while Instant::now() < grace_deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, true));
+        }
+        thread::sleep(Duration::from_millis(100));
     }
 
-    Ok(())
+    signal_process_tree(child.id(), "KILL");
+    Ok((child.wait()?, true))
 }
 
-pub fn run_command_without_local_path(cmd: &str, args: &[&str]) -> Result<(), Error> {
-    println!(
-        "{} {} {}",
-        "=>".green(),
-        cmd.green(),
-        args.join(" ").green()
-    );
-    let status = Command::new(cmd).args(args).status()?;
+/// Sends `signal` to `root` and all of its descendants, so that pipelines
+/// started through `bash -c` do not outlive a timed out command.
+fn signal_process_tree(root: u32, signal: &str) {
+    let parents = fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry.ok()?.file_name().to_str()?.parse::<u32>().ok()?;
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // The command name may contain spaces, the parent pid is the
+            // second field after its closing parenthesis.
+            let ppid = stat[stat.rfind(')')? + 1..]
+                .split_whitespace()
+                .nth(1)?
+                .parse::<u32>()
+                .ok()?;
+            Some((pid, ppid))
+        })
+        .collect::<Vec<_>>();
 
-    if !status.success() {
-        println!("Command failed: {}", status);
-        process::exit(status.code().unwrap_or(1));
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            parents
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
     }
 
-    Ok(())
+    let _ = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .args(tree.iter().map(|pid| pid.to_string()))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+/// Forwards a child's output to `writer` and the run log, returning the
+/// last [`OUTPUT_BUFFER_SIZE`] bytes. With a `prefix`, output is forwarded
+/// line by line, each line starting with the prefix.
+fn tee(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    log: Option<&RunLog>,
+    prefix: Option<&str>,
+) -> Vec<u8> {
+    let mut forward = |bytes: &[u8]| {
+        let _ = writer.write_all(bytes);
+        let _ = writer.flush();
+        if let Some(log) = log {
+            log.output(bytes);
+        }
+    };
+
+    let mut buffer = Vec::new();
+    let mut line = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                match prefix {
+                    None => forward(&chunk[..n]),
+                    Some(prefix) => {
+                        for &byte in &chunk[..n] {
+                            line.push(byte);
+                            if byte == b'\n' {
+                                forward(&[prefix.as_bytes(), &line].concat());
+                                line.clear();
+                            }
+                        }
+                    }
+                }
+                buffer.extend_from_slice(&chunk[..n]);
+                if buffer.len() > OUTPUT_BUFFER_SIZE {
+                    buffer.drain(..buffer.len() - OUTPUT_BUFFER_SIZE);
+                }
+            }
+        }
+    }
+
+    if let (Some(prefix), false) = (prefix, line.is_empty()) {
+        forward(&[prefix.as_bytes(), &line, b"\n"].concat());
+    }
+    buffer
+}
+
+fn tail_lines(output: &[u8], count: usize) -> Vec<String> {
+    let output = String::from_utf8_lossy(output);
+    let lines: Vec<&str> = output.lines().collect();
+    lines[lines.len().saturating_sub(count)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Records every invocation instead of running it.
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    invocations: Mutex<Vec<Invocation>>,
+    /// Output of captured commands by command line, see [`CommandRunner::capture`].
+    outputs: Vec<(String, String)>,
+    /// Output of captured commands by program, see [`RecordingRunner::with_program_output`].
+    program_outputs: Vec<(String, String)>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes capturing `command_line` return `output`. Other captured
+    /// commands fail as if they were not installed.
+    pub fn with_output(mut self, command_line: &str, output: &str) -> Self {
+        self.outputs
+            .push((command_line.to_string(), output.to_string()));
+        self
+    }
+
+    /// Makes capturing any `program` command without an output of its own
+    /// return `output`, for commands run on temporary paths.
+    pub fn with_program_output(mut self, program: &str, output: &str) -> Self {
+        self.program_outputs
+            .push((program.to_string(), output.to_string()));
+        self
+    }
+
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    pub fn command_lines(&self) -> Vec<String> {
+        self.invocations()
+            .iter()
+            .map(Invocation::command_line)
+            .collect()
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn execute(&self, invocation: &Invocation) -> Result<(), Error> {
+        self.invocations.lock().unwrap().push(invocation.clone());
+        Ok(())
+    }
+
+    fn capture(&self, invocation: &Invocation) -> Result<String, Error> {
+        self.execute(invocation)?;
+        let command_line = invocation.command_line();
+        let output = self
+            .outputs
+            .iter()
+            .find(|(line, _)| *line == command_line)
+            .or_else(|| {
+                self.program_outputs
+                    .iter()
+                    .find(|(program, _)| *program == invocation.program)
+            });
+        match output {
+            Some((_, output)) => Ok(output.clone()),
+            None => Err(CommandError {
+                program: invocation.program.clone(),
+                args: invocation.args.clone(),
+                code: Some(127),
+                signal: None,
+                timed_out: None,
+                stderr_tail: vec![],
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_runner_captures_argv() {
+        let runner = RecordingRunner::new();
+        runner
+            .run_without_local_path("sudo", &["apt-get", "update"])
+            .unwrap();
+        runner.run_without_local_path("true", &[]).unwrap();
+        assert_eq!(
+            runner.invocations(),
+            vec![
+                Invocation::new("sudo", &["apt-get", "update"]),
+                Invocation::new("true", &[]),
+            ]
+        );
+        assert_eq!(runner.command_lines(), vec!["sudo apt-get update", "true"]);
+    }
+
+    #[test]
+    fn test_env_runner_adds_env_and_path() {
+        let recorder = RecordingRunner::new();
+        let runner = EnvRunner::new(
+            &recorder,
+            vec![
+                ("EDITOR".to_string(), "nvim".to_string()),
+                ("GOPATH".to_string(), "~/go".to_string()),
+            ],
+            vec!["${HOME}/go/bin".to_string(), "/opt/bin".to_string()],
+        );
+        runner
+            .execute(&Invocation::new("go", &["version"]).env("PATH", "/usr/bin"))
+            .unwrap();
+
+        let home = std::env::var("HOME").unwrap();
+        let invocation = &recorder.invocations()[0];
+        assert_eq!(
+            invocation.env,
+            vec![
+                ("EDITOR".to_string(), "nvim".to_string()),
+                ("GOPATH".to_string(), format!("{home}/go")),
+                ("PATH".to_string(), "/usr/bin".to_string()),
+                (
+                    "PATH".to_string(),
+                    format!("{home}/go/bin:/opt/bin:/usr/bin")
+                ),
+            ]
+        );
+        assert_eq!(EnvRunner::expand("cost: $5 ${"), "cost: $5 ${");
+    }
+
+    #[test]
+    fn test_system_runner_returns_command_error() {
+        let err = SystemRunner::new()
+            .run_without_local_path("sh", &["-c", "echo first >&2; echo oops >&2; exit 3"])
+            .unwrap_err();
+        let err = err.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(err.program, "sh");
+        assert_eq!(err.code, Some(3));
+        assert_eq!(err.signal, None);
+        assert_eq!(err.exit_code(), 3);
+        assert_eq!(err.stderr_tail, vec!["first", "oops"]);
+    }
+
+    #[test]
+    fn test_system_runner_captures_output() {
+        let runner = SystemRunner::new();
+        let output = runner
+            .capture(&Invocation::new(
+                "sh",
+                &["-c", "echo 0.9.8; echo noise >&2"],
+            ))
+            .unwrap();
+        assert_eq!(output, "0.9.8\n");
+        let err = runner
+            .capture(&Invocation::new("sh", &["-c", "exit 2"]))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<CommandError>().unwrap().code, Some(2));
+    }
+
+    #[test]
+    fn test_system_runner_reports_signal() {
+        let err = SystemRunner::new()
+            .run_without_local_path("sh", &["-c", "kill -9 $$"])
+            .unwrap_err();
+        let err = err.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(err.code, None);
+        assert_eq!(err.signal, Some(9));
+        assert_eq!(err.exit_code(), 137);
+        assert_eq!(
+            err.to_string(),
+            "`sh -c kill -9 $$` was terminated by signal 9"
+        );
+    }
+
+    #[test]
+    fn test_system_runner_writes_run_log() {
+        let dir = std::env::temp_dir().join(format!("oh-my-droid-runner-{}", std::process::id()));
+        let runner = SystemRunner::with_log(RunLog::create_in(&dir).unwrap());
+        runner
+            .execute(&Invocation::new("sh", &["-c", "echo out; echo err >&2"]).env("FOO", "bar"))
+            .unwrap();
+
+        let content = std::fs::read_to_string(runner.log().unwrap().path()).unwrap();
+        assert!(content.contains("$ sh -c echo out; echo err >&2\n  env FOO=bar\n"));
+        assert!(content.contains("out\n"));
+        assert!(content.contains("err\n"));
+        assert!(content.contains("[exit code 0 after "));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_system_runner_passes_the_terminal_to_interactive_commands() {
+        let dir =
+            std::env::temp_dir().join(format!("oh-my-droid-interactive-{}", std::process::id()));
+        let runner = SystemRunner::with_log(RunLog::create_in(&dir).unwrap());
+        let mut invocation = Invocation::new("sh", &["-c", "echo not-logged"]);
+        invocation.interactive = true;
+        runner.execute(&invocation).unwrap();
+
+        let content = std::fs::read_to_string(runner.log().unwrap().path()).unwrap();
+        assert!(content.contains("$ sh -c echo not-logged\n"));
+        assert!(content.contains("[exit code 0 after "));
+        assert!(!content.lines().any(|line| line == "not-logged"));
+
+        invocation.args = vec!["-c".into(), "exit 3".into()];
+        let err = runner.execute(&invocation).unwrap_err();
+        assert_eq!(err.downcast_ref::<CommandError>().unwrap().code, Some(3));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interactive_runner_marks_commands() {
+        let recorder = RecordingRunner::new();
+        InteractiveRunner::new(&recorder)
+            .run_without_local_path("tailscale", &["up"])
+            .unwrap();
+        assert!(recorder.invocations()[0].interactive);
+    }
+
+    #[test]
+    fn test_tee_prefixes_lines() {
+        let mut output = Vec::new();
+        let buffer = tee(&b"one\ntwo"[..], &mut output, None, Some("[curl] "));
+        assert_eq!(output, b"[curl] one\n[curl] two\n");
+        assert_eq!(buffer, b"one\ntwo");
+    }
+
+    #[test]
+    fn test_labeled_runner_keeps_inner_label() {
+        let recorder = RecordingRunner::new();
+        let runner = LabeledRunner::new(&recorder, "stow");
+        runner.run_without_local_path("git", &["pull"]).unwrap();
+        let mut invocation = Invocation::new("true", &[]);
+        invocation.label = Some("npm".into());
+        runner.execute(&invocation).unwrap();
+
+        let labels = recorder
+            .invocations()
+            .into_iter()
+            .map(|i| i.label)
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec![Some("stow".into()), Some("npm".into())]);
+    }
+
+    #[test]
+    fn test_system_runner_times_out_process_tree() {
+        let mut invocation = Invocation::new("sh", &["-c", "sleep 30 | cat"]);
+        invocation.timeout = Some(Duration::from_millis(200));
+        let started = Instant::now();
+        let err = SystemRunner::new().execute(&invocation).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let err = err.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(err.timed_out, Some(Duration::from_millis(200)));
+        assert_eq!(err.exit_code(), 124);
+    }
+
+    /// Fails the first `failures` invocations with a [`CommandError`].
+    struct FlakyRunner {
+        failures: Mutex<u32>,
+        recorder: RecordingRunner,
+    }
+
+    impl CommandRunner for FlakyRunner {
+        fn execute(&self, invocation: &Invocation) -> Result<(), Error> {
+            self.recorder.execute(invocation)?;
+            let mut failures = self.failures.lock().unwrap();
+            if *failures == 0 {
+                return Ok(());
+            }
+            *failures -= 1;
+            Err(CommandError {
+                program: invocation.program.clone(),
+                args: invocation.args.clone(),
+                code: Some(6),
+                signal: None,
+                timed_out: None,
+                stderr_tail: vec![],
+            }
+            .into())
+        }
+
+        fn capture(&self, invocation: &Invocation) -> Result<String, Error> {
+            self.recorder.capture(invocation)
+        }
+    }
+
+    fn flaky(failures: u32) -> FlakyRunner {
+        FlakyRunner {
+            failures: Mutex::new(failures),
+            recorder: RecordingRunner::new(),
+        }
+    }
+
+    #[test]
+    fn test_retry_runner_retries_network_commands() {
+        let inner = flaky(2);
+        let policy = RetryPolicy {
+            timeout: Some(Duration::from_secs(60)),
+            retries: 2,
+            backoff: Duration::ZERO,
+        };
+        RetryRunner::new(&inner, policy)
+            .run_network("curl", &["-fsSL", "https://pkgx.sh"])
+            .unwrap();
+
+        let invocations = inner.recorder.invocations();
+        assert_eq!(invocations.len(), 3);
+        assert!(
+            invocations
+                .iter()
+                .all(|i| i.timeout == Some(Duration::from_secs(60)))
+        );
+    }
+
+    #[test]
+    fn test_retry_runner_gives_up_after_retries() {
+        let inner = flaky(5);
+        let policy = RetryPolicy {
+            timeout: None,
+            retries: 1,
+            backoff: Duration::ZERO,
+        };
+        let err = RetryRunner::new(&inner, policy)
+            .run_network("git", &["pull"])
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<CommandError>().unwrap().code, Some(6));
+        assert_eq!(inner.recorder.invocations().len(), 2);
+    }
+
+    #[test]
+    fn test_retry_runner_ignores_local_commands() {
+        let inner = flaky(1);
+        let runner = RetryRunner::new(&inner, RetryPolicy::default());
+        assert!(runner.run("mise", &["use", "-g", "node@latest"]).is_err());
+        assert_eq!(inner.recorder.invocations().len(), 1);
+        assert_eq!(inner.recorder.invocations()[0].timeout, None);
+    }
+
+    #[test]
+    fn test_recording_runner_captures_local_path() {
+        let runner = RecordingRunner::new();
+        runner.run("git", &["pull"]).unwrap();
+        let invocations = runner.invocations();
+        let (key, value) = &invocations[0].env[0];
+        assert_eq!(key, "PATH");
+        assert!(value.starts_with("/nix/var/nix/profiles/default/bin:"));
+        assert!(value.contains("/.local/bin:"));
+    }
 }
@@ -1,19 +1,670 @@
 use anyhow::{Context, Error, Result};
 use owo_colors::OwoColorize;
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::Write,
-    process::Command,
-};
+use std::{borrow::Cow, collections::HashMap, fmt, process::Command, time::Duration};
+use url::Url;
 
-use crate::{apply::SetupStep, diff::Diff};
+use crate::{
+    apply::{BUILTIN_INSTALLERS, SetupStep, sort_steps},
+    cmd::setup::parse_config_path,
+    command::{
+        CommandRunner, EnvRunner, InteractiveRunner, LabeledRunner, RetryPolicy, RetryRunner,
+    },
+    diff::Diff,
+    git::extract_version,
+    journal::Journal,
+    lock::Lock,
+    scheduler::run_steps,
+    shell::Shell,
+    teardown::Teardown,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The Oh My Posh prompt. `config_path` takes precedence over
+/// `config_url`, which takes precedence over `theme`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct OhMyPosh {
+    /// One of the themes installed in `~/.cache/oh-my-posh/themes`.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub theme: Option<String>,
+
+    /// A local configuration file, e.g. from the stowed dotfiles.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_path: Option<String>,
+
+    /// A configuration file downloaded on apply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_url: Option<String>,
+}
+
+impl OhMyPosh {
+    pub const DEFAULT_THEME: &str = "tokyonight_storm";
+    pub const THEMES_DIR: &str = ".cache/oh-my-posh/themes";
+
+    pub fn theme(&self) -> &str {
+        self.theme.as_deref().unwrap_or(Self::DEFAULT_THEME)
+    }
+
+    /// The configuration file passed to `oh-my-posh init`, relative to the
+    /// home directory when it starts with `~/`.
+    pub fn config_file(&self) -> String {
+        if let Some(path) = &self.config_path {
+            return path.clone();
+        }
+        if let Some(url) = &self.config_url {
+            return format!("~/.oh-my-droid/{}", Self::downloaded_file_name(url));
+        }
+        format!("~/{}/{}.omp.json", Self::THEMES_DIR, self.theme())
+    }
+
+    /// Keeps the extension of the downloaded file, oh-my-posh picks the
+    /// format (json, yaml or toml) from it.
+    fn downloaded_file_name(url: &str) -> String {
+        let extension = Url::parse(url)
+            .ok()
+            .and_then(|url| {
+                let name = url.path_segments()?.next_back()?.to_string();
+                let (_, extension) = name.rsplit_once('.')?;
+                Some(extension.to_string())
+            })
+            .filter(|ext| ["json", "jsonc", "yaml", "yml", "toml"].contains(&ext.as_str()))
+            .unwrap_or_else(|| "json".to_string());
+        format!("oh-my-posh.omp.{extension}")
+    }
+}
+
+/// ble.sh, either `true` or a table pinning the version to install:
+///
+/// ```toml
+/// ["ble.sh"]
+/// version = "v0.4.0-devel3"
+/// prefix = "~/.local"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BleShConfig {
+    Enabled(bool),
+    Options {
+        /// A tag, branch or commit of the ble.sh repository.
+        #[serde(alias = "ref", skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
+
+        /// The `make install` prefix.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+    },
+}
+
+impl BleShConfig {
+    pub const DEFAULT_VERSION: &str = "master";
+    pub const DEFAULT_PREFIX: &str = "~/.local";
+    /// The ble.sh checkout it is built from, relative to the home directory.
+    pub const SOURCE_DIR: &str = ".oh-my-droid/ble.sh";
+
+    pub fn enabled(&self) -> bool {
+        match self {
+            BleShConfig::Enabled(enabled) => *enabled,
+            BleShConfig::Options { .. } => true,
+        }
+    }
+
+    pub fn version(&self) -> &str {
+        match self {
+            BleShConfig::Options {
+                version: Some(version),
+                ..
+            } => version,
+            _ => Self::DEFAULT_VERSION,
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        match self {
+            BleShConfig::Options {
+                prefix: Some(prefix),
+                ..
+            } => prefix,
+            _ => Self::DEFAULT_PREFIX,
+        }
+    }
+
+    /// The installed script sourced by the init script.
+    pub fn script(&self) -> String {
+        format!("{}/share/blesh/ble.sh", self.prefix().trim_end_matches('/'))
+    }
+}
+
+impl fmt::Display for BleShConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BleShConfig::Enabled(enabled) => write!(f, "{}", enabled),
+            BleShConfig::Options { .. } => {
+                write!(f, "{}, prefix {}", self.version(), self.prefix())
+            }
+        }
+    }
+}
+
+/// When zoxide updates its database, passed to `zoxide init --hook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoxideHook {
+    None,
+    Prompt,
+    Pwd,
+}
+
+impl ZoxideHook {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZoxideHook::None => "none",
+            ZoxideHook::Prompt => "prompt",
+            ZoxideHook::Pwd => "pwd",
+        }
+    }
+}
+
+/// zoxide, either `true` or a table with its version and `zoxide init`
+/// options:
+///
+/// ```toml
+/// [zoxide]
+/// version = "0.9.8"
+/// cmd = "cd"
+/// hook = "pwd"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ZoxideConfig {
+    Enabled(bool),
+    Options {
+        /// Any installed version is kept when not set.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
+
+        /// Replaces the `z` and `zi` commands, e.g. `cd` and `cdi`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cmd: Option<String>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hook: Option<ZoxideHook>,
+    },
+}
+
+impl ZoxideConfig {
+    pub fn enabled(&self) -> bool {
+        match self {
+            ZoxideConfig::Enabled(enabled) => *enabled,
+            ZoxideConfig::Options { .. } => true,
+        }
+    }
+
+    /// The requested version, without a leading `v`.
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            ZoxideConfig::Options {
+                version: Some(version),
+                ..
+            } => Some(version.trim_start_matches('v')),
+            _ => None,
+        }
+    }
+
+    /// The `zoxide init` options, e.g. `--cmd cd --hook pwd`.
+    pub fn init_args(&self) -> String {
+        let mut args = String::new();
+        if let ZoxideConfig::Options { cmd, hook, .. } = self {
+            if let Some(cmd) = cmd {
+                args.push_str(&format!(" --cmd {}", cmd));
+            }
+            if let Some(hook) = hook {
+                args.push_str(&format!(" --hook {}", hook.name()));
+            }
+        }
+        args.trim_start().to_string()
+    }
+}
+
+impl fmt::Display for ZoxideConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoxideConfig::Enabled(enabled) => write!(f, "{}", enabled),
+            ZoxideConfig::Options { .. } => {
+                write!(f, "{}", self.version().unwrap_or("any version"))?;
+                let args = self.init_args();
+                if !args.is_empty() {
+                    write!(f, ", {}", args)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An `apt-get` package, either its name or a table:
+///
+/// ```toml
+/// apt-get = ["git", { name = "nodejs", version = "20.*", hold = true }]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AptPackage {
+    Name(String),
+    Options {
+        name: String,
+
+        /// Pinned in the apt preferences, may contain `*` wildcards.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
+
+        /// Keeps apt from upgrading or removing the package, see `apt-mark hold`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        hold: bool,
+
+        #[serde(
+            default,
+            rename = "no-install-recommends",
+            skip_serializing_if = "std::ops::Not::not"
+        )]
+        no_install_recommends: bool,
+    },
+}
+
+impl AptPackage {
+    pub fn name(&self) -> &str {
+        match self {
+            AptPackage::Name(name) | AptPackage::Options { name, .. } => name,
+        }
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            AptPackage::Options {
+                version: Some(version),
+                ..
+            } => Some(version),
+            _ => None,
+        }
+    }
+
+    pub fn hold(&self) -> bool {
+        matches!(self, AptPackage::Options { hold: true, .. })
+    }
+
+    pub fn no_install_recommends(&self) -> bool {
+        matches!(
+            self,
+            AptPackage::Options {
+                no_install_recommends: true,
+                ..
+            }
+        )
+    }
+}
+
+impl From<&str> for AptPackage {
+    fn from(name: &str) -> Self {
+        AptPackage::Name(name.to_string())
+    }
+}
+
+impl fmt::Display for AptPackage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(version) = self.version() {
+            write!(f, "={}", version)?;
+        }
+        if self.hold() {
+            write!(f, " (held)")?;
+        }
+        if self.no_install_recommends() {
+            write!(f, " (no recommends)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A `[curl]` installer script, run with bash. The table form pins the
+/// checksum of the script, which is refused when it does not match, and
+/// sets how it is run:
+///
+/// ```toml
+/// [curl]
+/// bun = "https://bun.sh/install"
+/// deno = { url = "https://deno.land/install.sh", sha256 = "..." }
+///
+/// [curl.rustup]
+/// url = "https://sh.rustup.rs"
+/// args = ["-y", "--default-toolchain", "stable"]
+/// env = { RUSTUP_HOME = "~/.rustup" }
+/// interpreter = "sh"
+/// creates = "~/.cargo/bin/rustup"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CurlInstaller {
+    Url(String),
+    Script(CurlScript),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CurlScript {
+    pub url: String,
+
+    /// Expected sha256 of the script, see `oh-my-droid pin-installers`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    /// Arguments passed to the script.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+
+    /// Environment variables set for the script, expanded like `[env]`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+
+    /// The shell running the script, bash by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interpreter: Option<Interpreter>,
+
+    /// The installer is skipped while this path exists.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creates: Option<String>,
+
+    /// Runs the script as root.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sudo: bool,
+}
+
+/// The shell a `[curl]` installer script is run with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpreter {
+    Sh,
+    #[default]
+    Bash,
+}
+
+impl Interpreter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interpreter::Sh => "sh",
+            Interpreter::Bash => "bash",
+        }
+    }
+}
+
+impl CurlScript {
+    /// The environment variables of the script, sorted by name.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        let mut env = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        env.sort();
+        env
+    }
+}
+
+impl CurlInstaller {
+    pub fn url(&self) -> &str {
+        match self {
+            CurlInstaller::Url(url) | CurlInstaller::Script(CurlScript { url, .. }) => url,
+        }
+    }
+
+    pub fn sha256(&self) -> Option<&str> {
+        match self {
+            CurlInstaller::Script(script) => script.sha256.as_deref(),
+            CurlInstaller::Url(_) => None,
+        }
+    }
+
+    /// The installer in its table form, with the defaults of a plain URL.
+    pub fn script(&self) -> Cow<'_, CurlScript> {
+        match self {
+            CurlInstaller::Script(script) => Cow::Borrowed(script),
+            CurlInstaller::Url(url) => Cow::Owned(CurlScript {
+                url: url.clone(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Records the expected sha256 of the script.
+    pub fn pin(&mut self, sha256: &str) {
+        let mut script = self.script().into_owned();
+        script.sha256 = Some(sha256.to_string());
+        *self = CurlInstaller::Script(script);
+    }
+}
+
+impl From<&str> for CurlInstaller {
+    fn from(url: &str) -> Self {
+        CurlInstaller::Url(url.to_string())
+    }
+}
+
+impl fmt::Display for CurlInstaller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let script = self.script();
+        write!(f, "{}", script.url)?;
+        if !script.args.is_empty() {
+            write!(f, " {}", script.args.join(" "))?;
+        }
+        if let Some(interpreter) = script.interpreter {
+            write!(f, " ({})", interpreter.name())?;
+        }
+        if script.sudo {
+            write!(f, " (sudo)")?;
+        }
+        let env = script.env_vars();
+        if !env.is_empty() {
+            let env = env
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>();
+            write!(f, " (env {})", env.join(" "))?;
+        }
+        if let Some(creates) = &script.creates {
+            write!(f, " (creates {})", creates)?;
+        }
+        if let Some(sha256) = &script.sha256 {
+            write!(f, " (sha256 {})", sha256)?;
+        }
+        Ok(())
+    }
+}
+
+/// An apt repository, written to `/etc/apt/sources.list.d/{name}.sources`
+/// with its signing key in `/etc/apt/keyrings/{name}.gpg`:
+///
+/// ```toml
+/// [apt.repositories.vscode]
+/// uri = "https://packages.microsoft.com/repos/code"
+/// suite = "stable"
+/// components = ["main"]
+/// key_url = "https://packages.microsoft.com/keys/microsoft.asc"
+/// fingerprint = "BC528686B50D79E339D3721CEB3E94ADBE1229CF"
+/// arch = ["amd64", "arm64"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AptRepository {
+    pub uri: String,
+    pub suite: String,
+
+    #[serde(default = "default_components")]
+    pub components: Vec<String>,
+
+    pub key_url: String,
+
+    /// The fingerprint of the signing key, the downloaded key is rejected
+    /// when it does not match.
+    pub fingerprint: String,
+
+    /// Architectures to fetch, the native one when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arch: Vec<String>,
+}
+
+fn default_components() -> Vec<String> {
+    vec!["main".to_string()]
+}
+
+impl AptRepository {
+    /// The repositories set up unless disabled with e.g. `vscode = false`.
+    pub fn defaults() -> Vec<(String, AptRepository)> {
+        vec![
+            (
+                "vscode".to_string(),
+                AptRepository {
+                    uri: "https://packages.microsoft.com/repos/code".to_string(),
+                    suite: "stable".to_string(),
+                    components: default_components(),
+                    key_url: "https://packages.microsoft.com/keys/microsoft.asc".to_string(),
+                    fingerprint: "BC528686B50D79E339D3721CEB3E94ADBE1229CF".to_string(),
+                    arch: vec!["amd64".into(), "arm64".into(), "armhf".into()],
+                },
+            ),
+            (
+                "mise".to_string(),
+                AptRepository {
+                    uri: "https://mise.jdx.dev/deb".to_string(),
+                    suite: "stable".to_string(),
+                    components: default_components(),
+                    key_url: "https://mise.jdx.dev/gpg-key.pub".to_string(),
+                    fingerprint: "24853EC9F655CE80B48E6C3A8B81C9D17413A06D".to_string(),
+                    arch: vec!["amd64".into(), "arm64".into()],
+                },
+            ),
+        ]
+    }
+
+    /// The fingerprint in upper case, without the spaces it is often
+    /// printed with.
+    pub fn normalized_fingerprint(&self) -> String {
+        self.fingerprint
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_uppercase()
+    }
+
+    /// The deb822 `.sources` file of the repository, signed by `keyring`.
+    pub fn sources(&self, keyring: &str) -> String {
+        let mut sources = format!(
+            "# Managed by oh-my-droid, key fingerprint {}\nTypes: deb\nURIs: {}\nSuites: {}\nComponents: {}\n",
+            self.normalized_fingerprint(),
+            self.uri,
+            self.suite,
+            self.components.join(" ")
+        );
+        if !self.arch.is_empty() {
+            sources.push_str(&format!("Architectures: {}\n", self.arch.join(" ")));
+        }
+        sources.push_str(&format!("Signed-By: {}\n", keyring));
+        sources
+    }
+}
+
+impl fmt::Display for AptRepository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.uri,
+            self.suite,
+            self.components.join(" ")
+        )
+    }
+}
+
+/// A configured apt repository, or `false` to disable a built-in one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AptRepositoryEntry {
+    Enabled(bool),
+    Repository(AptRepository),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AptConfig {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub repositories: HashMap<String, AptRepositoryEntry>,
+}
+
+/// Used when the prompt is Oh My Posh without an `[oh_my_posh]` table.
+static DEFAULT_OH_MY_POSH: OhMyPosh = OhMyPosh {
+    theme: None,
+    config_path: None,
+    config_url: None,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptProvider {
+    OhMyPosh,
+    Starship,
+}
+
+impl PromptProvider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PromptProvider::OhMyPosh => "oh-my-posh",
+            PromptProvider::Starship => "starship",
+        }
+    }
+}
+
+/// The prompt to set up. Oh My Posh is configured in its own
+/// `[oh_my_posh]` table, Starship with a preset or an inline
+/// `starship.toml`:
+///
+/// ```toml
+/// [prompt]
+/// provider = "starship"
+/// preset = "tokyo-night"
+///
+/// # or
+/// [prompt.config]
+/// add_newline = false
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptConfig {
+    pub provider: PromptProvider,
+
+    /// A Starship preset, see `starship preset --list`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+
+    /// The contents of `starship.toml`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<toml::Table>,
+}
+
+impl PromptConfig {
+    /// Where the Starship configuration is written, relative to the home
+    /// directory. Without a preset or inline config, Starship reads its
+    /// default `~/.config/starship.toml`, e.g. from the stowed dotfiles.
+    pub const STARSHIP_CONFIG: &str = ".oh-my-droid/starship.toml";
+
+    pub fn manages_starship_config(&self) -> bool {
+        self.provider == PromptProvider::Starship
+            && (self.preset.is_some() || self.config.is_some())
+    }
+}
+
+impl fmt::Display for PromptConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.provider.name())?;
+        if let Some(preset) = &self.preset {
+            write!(f, ", preset {}", preset)?;
+        }
+        if let Some(config) = &self.config {
+            write!(f, ", inline config ({} keys)", config.len())?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +676,148 @@ pub struct SshConfig {
     pub authorized_keys: Option<Vec<String>>,
 }
 
+/// The shell to integrate with, either its name or a table with more
+/// options:
+///
+/// ```toml
+/// [shell]
+/// name = "zsh"
+/// chsh = true
+/// manage_rc = false
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ShellConfig {
+    Name(Shell),
+    Options {
+        /// Detected from `$SHELL` when not set.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<Shell>,
+
+        /// Make the shell the user's login shell.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        chsh: bool,
+
+        /// Source the init script from the shell's rc file, true by default.
+        /// Disable it to keep the rc file in a dotfiles repository and call
+        /// `oh-my-droid shell-init` from it instead.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        manage_rc: Option<bool>,
+    },
+}
+
+impl ShellConfig {
+    pub fn name(&self) -> Option<Shell> {
+        match self {
+            ShellConfig::Name(shell) => Some(*shell),
+            ShellConfig::Options { name, .. } => *name,
+        }
+    }
+
+    pub fn chsh(&self) -> bool {
+        matches!(self, ShellConfig::Options { chsh: true, .. })
+    }
+
+    pub fn manage_rc(&self) -> bool {
+        !matches!(
+            self,
+            ShellConfig::Options {
+                manage_rc: Some(false),
+                ..
+            }
+        )
+    }
+}
+
+impl fmt::Display for ShellConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(shell) => write!(f, "{}", shell)?,
+            None => write!(f, "detected")?,
+        }
+        if self.chsh() {
+            write!(f, ", login shell")?;
+        }
+        if !self.manage_rc() {
+            write!(f, ", rc file not managed")?;
+        }
+        Ok(())
+    }
+}
+
+/// Timeout and retry settings for network-bound commands. Unset fields fall
+/// back to the enclosing policy, then to [`RetryPolicy::default`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Seconds after which a command is terminated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+
+    /// Seconds to wait before the first retry, doubled after each attempt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<u64>,
+}
+
+impl RetryConfig {
+    /// Returns `self` with unset fields taken from `fallback`.
+    pub fn or(&self, fallback: &RetryConfig) -> RetryConfig {
+        RetryConfig {
+            timeout: self.timeout.or(fallback.timeout),
+            retries: self.retries.or(fallback.retries),
+            backoff: self.backoff.or(fallback.backoff),
+        }
+    }
+
+    pub fn policy(&self) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            timeout: self.timeout.map(Duration::from_secs).or(default.timeout),
+            retries: self.retries.unwrap_or(default.retries),
+            backoff: self
+                .backoff
+                .map(Duration::from_secs)
+                .unwrap_or(default.backoff),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    #[serde(flatten)]
+    pub defaults: RetryConfig,
+
+    /// Per-step overrides, keyed by step name (e.g. `curl`, `apt-get`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps: Option<HashMap<String, RetryConfig>>,
+}
+
+impl NetworkConfig {
+    /// Resolves the policy for `step`. `overrides` (from the command line)
+    /// take precedence over the per-step and global settings.
+    pub fn policy_for(&self, step: &str, overrides: &RetryConfig) -> RetryPolicy {
+        let step_config = self
+            .steps
+            .as_ref()
+            .and_then(|steps| steps.get(step))
+            .cloned()
+            .unwrap_or_default();
+        overrides.or(&step_config.or(&self.defaults)).policy()
+    }
+}
+
+/// How [`Configuration::setup_environment`] runs the setup steps.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    pub dry_run: bool,
+    /// Maximum number of setup steps running at the same time.
+    pub jobs: usize,
+    /// Timeout and retry settings given on the command line.
+    pub retry: RetryConfig,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Configuration {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -38,65 +831,420 @@ pub struct Configuration {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "apt-get")]
-    pub apt_get: Option<Vec<String>>,
+    pub apt_get: Option<Vec<AptPackage>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apt: Option<AptConfig>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pkgx: Option<HashMap<String, String>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub curl: Option<HashMap<String, String>>,
+    pub curl: Option<HashMap<String, CurlInstaller>>,
+
+    /// The sha256 of the installer scripts of the built-in steps, by step
+    /// name, see `oh-my-droid pin-installers`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installers: Option<HashMap<String, String>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "ble.sh")]
-    pub blesh: Option<bool>,
+    pub blesh: Option<BleShConfig>,
+
+    /// The prompt provider, Oh My Posh when only `[oh_my_posh]` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<PromptConfig>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub oh_my_posh: Option<OhMyPosh>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub zoxide: Option<bool>,
+    pub zoxide: Option<ZoxideConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<HashMap<String, String>>,
+
+    /// Shell functions by name, with their body in the syntax of the
+    /// configured shell.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functions: Option<HashMap<String, String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tailscale: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub neofetch: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doppler: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub npm: Option<HashMap<String, String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<ShellConfig>,
+
+    /// Environment variables exported by the shell init script and set for
+    /// the setup commands.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, String>>,
+
+    /// Directories prepended to `PATH`, first entry first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkConfig>,
+}
+
+impl Configuration {
+    pub fn empty() -> Self {
+        Self {
+            stow: None,
+            mise: None,
+            nix: None,
+            apt_get: None,
+            apt: None,
+            pkgx: None,
+            curl: None,
+            installers: None,
+            blesh: None,
+            prompt: None,
+            oh_my_posh: None,
+            zoxide: None,
+            alias: None,
+            functions: None,
+            tailscale: None,
+            ssh: None,
+            neofetch: None,
+            doppler: None,
+            npm: None,
+            shell: None,
+            env: None,
+            path: None,
+            network: None,
+        }
+    }
+
+    /// A fingerprint of the configuration, equal for equal configurations
+    /// whatever the order of their tables' entries.
+    pub fn fingerprint(&self) -> Result<String> {
+        // toml tables are sorted by key, unlike the HashMap sections
+        let canonical = toml::Value::try_from(self)
+            .context("Failed to serialize config")?
+            .to_string();
+        // FNV-1a, which unlike the std hasher is the same across builds
+        let hash = canonical.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+        Ok(format!("{hash:016x}"))
+    }
+
+    /// The apt preferences pinning the packages with a version, if any.
+    pub fn apt_preferences(&self) -> Option<String> {
+        let mut pinned = self
+            .apt_get
+            .iter()
+            .flatten()
+            .filter_map(|package| Some((package.name(), package.version()?)))
+            .collect::<Vec<_>>();
+        if pinned.is_empty() {
+            return None;
+        }
+        pinned.sort();
+        let preferences = pinned
+            .iter()
+            .map(|(name, version)| {
+                format!("Package: {name}\nPin: version {version}\nPin-Priority: 1001\n")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some(format!("# Managed by oh-my-droid\n{preferences}"))
+    }
+
+    /// The built-in apt repositories with the configured ones added or
+    /// disabled, sorted by name.
+    pub fn apt_repositories(&self) -> Vec<(String, AptRepository)> {
+        let mut repositories = AptRepository::defaults();
+        let mut entries = self
+            .apt
+            .iter()
+            .flat_map(|apt| apt.repositories.iter())
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(name, _)| *name);
+        for (name, entry) in entries {
+            match entry {
+                AptRepositoryEntry::Enabled(true) => {}
+                AptRepositoryEntry::Enabled(false) => repositories.retain(|(n, _)| n != name),
+                AptRepositoryEntry::Repository(repository) => {
+                    repositories.retain(|(n, _)| n != name);
+                    repositories.push((name.clone(), repository.clone()));
+                }
+            }
+        }
+        repositories.sort_by(|(a, _), (b, _)| a.cmp(b));
+        repositories
+    }
+
+    /// The prompt set up by the init script, if any.
+    pub fn prompt_provider(&self) -> Option<PromptProvider> {
+        match (&self.prompt, &self.oh_my_posh) {
+            (Some(prompt), _) => Some(prompt.provider),
+            (None, Some(_)) => Some(PromptProvider::OhMyPosh),
+            (None, None) => None,
+        }
+    }
+
+    /// The steps whose built-in installer script the configuration uses,
+    /// as named in `[installers]`. A pinned zoxide version is installed
+    /// from its release instead.
+    pub fn builtin_installers(&self) -> Vec<&'static str> {
+        let provider = self.prompt_provider();
+        let used = [
+            ("doppler", self.doppler == Some(true)),
+            ("nix", self.nix.is_some()),
+            ("oh_my_posh", provider == Some(PromptProvider::OhMyPosh)),
+            (
+                "pkgx",
+                self.pkgx.as_ref().is_some_and(|pkgx| !pkgx.is_empty()),
+            ),
+            ("starship", provider == Some(PromptProvider::Starship)),
+            ("tailscale", self.tailscale == Some(true)),
+            (
+                "zoxide",
+                self.zoxide
+                    .as_ref()
+                    .is_some_and(|zoxide| zoxide.enabled() && zoxide.version().is_none()),
+            ),
+        ];
+        used.into_iter()
+            .filter(|(_, used)| *used)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// The `[env]` variables, sorted by name.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        let mut env = self
+            .env
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        env.sort();
+        env
+    }
+
+    /// The configured shell, detected from `$SHELL` when not set.
+    pub fn shell(&self) -> Shell {
+        self.shell
+            .as_ref()
+            .and_then(ShellConfig::name)
+            .unwrap_or_else(Shell::detect)
+    }
+
+    /// Whether the rc file of the shell sources the init script, see
+    /// `manage_rc` in [`ShellConfig`].
+    pub fn manage_rc(&self) -> bool {
+        self.shell.as_ref().is_none_or(ShellConfig::manage_rc)
+    }
+
+    /// Resolves the retry policy for `step`, see [`NetworkConfig::policy_for`].
+    pub fn retry_policy(&self, step: &str, overrides: &RetryConfig) -> RetryPolicy {
+        self.network
+            .clone()
+            .unwrap_or_default()
+            .policy_for(step, overrides)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for name in self.env.iter().flat_map(|env| env.keys()) {
+            if !is_identifier(name) {
+                return Err(Error::msg(format!(
+                    "Invalid environment variable name in [env]: {}",
+                    name
+                )));
+            }
+        }
+
+        for name in self.alias.iter().flat_map(|alias| alias.keys()) {
+            if !is_alias_name(name) {
+                return Err(Error::msg(format!(
+                    "Invalid alias name in [alias]: {}",
+                    name
+                )));
+            }
+        }
+
+        for name in self.functions.iter().flat_map(|functions| functions.keys()) {
+            if !is_function_name(name) {
+                return Err(Error::msg(format!(
+                    "Invalid function name in [functions]: {}",
+                    name
+                )));
+            }
+        }
+
+        if let Some(url) = self
+            .oh_my_posh
+            .as_ref()
+            .and_then(|omp| omp.config_url.as_ref())
+        {
+            let valid = Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+            if !valid {
+                return Err(Error::msg(format!(
+                    "Invalid config_url in [oh_my_posh]: {}",
+                    url
+                )));
+            }
+        }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub alias: Option<HashMap<String, String>>,
+        for package in self.apt_get.iter().flatten() {
+            let valid = |value: &str| !value.is_empty() && !value.contains(char::is_whitespace);
+            if !valid(package.name()) || package.version().is_some_and(|v| !valid(v)) {
+                return Err(Error::msg(format!("Invalid apt-get package: {}", package)));
+            }
+        }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub tailscale: Option<bool>,
+        for (name, installer) in self.curl.iter().flatten() {
+            if let Some(key) = installer
+                .script()
+                .env
+                .keys()
+                .find(|key| !is_identifier(key))
+            {
+                return Err(Error::msg(format!(
+                    "Invalid environment variable name for curl installer {}: {}",
+                    name, key
+                )));
+            }
+            if installer.script().creates.as_deref() == Some("") {
+                return Err(Error::msg(format!(
+                    "Empty creates path for curl installer {}",
+                    name
+                )));
+            }
+            if let Some(sha256) = installer.sha256()
+                && !is_sha256(sha256)
+            {
+                return Err(Error::msg(format!(
+                    "Invalid sha256 for curl installer {}: {}",
+                    name, sha256
+                )));
+            }
+        }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub ssh: Option<SshConfig>,
+        for (name, sha256) in self.installers.iter().flatten() {
+            if !BUILTIN_INSTALLERS.iter().any(|(step, _)| step == name) {
+                return Err(Error::msg(format!(
+                    "Unknown installer in [installers]: {}, expected one of {}",
+                    name,
+                    BUILTIN_INSTALLERS.map(|(step, _)| step).join(", ")
+                )));
+            }
+            if !is_sha256(sha256) {
+                return Err(Error::msg(format!(
+                    "Invalid sha256 for installer {}: {}",
+                    name, sha256
+                )));
+            }
+        }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub neofetch: Option<bool>,
+        for (name, value) in self.nix.iter().flatten() {
+            if name == HOME_MANAGER {
+                if !value.contains(':') && !value.starts_with(['/', '.']) {
+                    return Err(Error::msg(format!(
+                        "Invalid [nix] home-manager = \"{}\", expected a flake reference such as github:org/home#phone",
+                        value
+                    )));
+                }
+                continue;
+            }
+            if value != "latest" && !value.contains('#') && !value.contains(':') {
+                return Err(Error::msg(format!(
+                    "Invalid nix package {} = \"{}\", expected \"latest\", nixpkgs#<package> or a flake reference",
+                    name, value
+                )));
+            }
+        }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub doppler: Option<bool>,
+        let defaults = AptRepository::defaults();
+        for (name, entry) in self.apt.iter().flat_map(|apt| apt.repositories.iter()) {
+            if !is_file_name(name) {
+                return Err(Error::msg(format!(
+                    "Invalid repository name in [apt.repositories]: {}",
+                    name
+                )));
+            }
+            match entry {
+                AptRepositoryEntry::Enabled(_) if !defaults.iter().any(|(n, _)| n == name) => {
+                    return Err(Error::msg(format!(
+                        "[apt.repositories] {} is not a built-in repository, it needs a uri, suite, key_url and fingerprint",
+                        name
+                    )));
+                }
+                AptRepositoryEntry::Repository(repository) => {
+                    let fingerprint = repository.normalized_fingerprint();
+                    if fingerprint.len() != 40
+                        || !fingerprint.chars().all(|c| c.is_ascii_hexdigit())
+                    {
+                        return Err(Error::msg(format!(
+                            "Invalid key fingerprint for apt repository {}: {}",
+                            name, repository.fingerprint
+                        )));
+                    }
+                    for url in [&repository.uri, &repository.key_url] {
+                        if !Url::parse(url)
+                            .is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+                        {
+                            return Err(Error::msg(format!(
+                                "Invalid URL for apt repository {}: {}",
+                                name, url
+                            )));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub npm: Option<HashMap<String, String>>,
-}
+        if let Some(ZoxideConfig::Options { cmd: Some(cmd), .. }) = &self.zoxide
+            && !is_identifier(cmd)
+        {
+            return Err(Error::msg(format!("Invalid cmd in [zoxide]: {}", cmd)));
+        }
 
-impl Configuration {
-    pub fn empty() -> Self {
-        Self {
-            stow: None,
-            mise: None,
-            nix: None,
-            apt_get: None,
-            pkgx: None,
-            curl: None,
-            blesh: None,
-            oh_my_posh: None,
-            zoxide: None,
-            alias: None,
-            tailscale: None,
-            ssh: None,
-            neofetch: None,
-            doppler: None,
-            npm: None,
+        if let Some(prompt) = &self.prompt {
+            if prompt.preset.is_some() && prompt.config.is_some() {
+                return Err(Error::msg(
+                    "[prompt] takes either a preset or an inline config, not both",
+                ));
+            }
+            if prompt.provider != PromptProvider::Starship
+                && (prompt.preset.is_some() || prompt.config.is_some())
+            {
+                return Err(Error::msg(format!(
+                    "[prompt] preset and config are Starship settings, configure {} in [oh_my_posh]",
+                    prompt.provider.name()
+                )));
+            }
+            let valid_preset = |preset: &String| {
+                !preset.is_empty()
+                    && preset
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            };
+            if let Some(preset) = prompt.preset.as_ref().filter(|p| !valid_preset(p)) {
+                return Err(Error::msg(format!(
+                    "Invalid Starship preset in [prompt]: {}",
+                    preset
+                )));
+            }
         }
-    }
 
-    pub fn validate(&self) -> Result<()> {
         if self.npm.is_some() {
             let has_node_via_mise = self
                 .mise
@@ -119,9 +1267,15 @@ impl Configuration {
         Ok(())
     }
 
-    pub fn setup_environment(&self, dry_run: bool, diffs: Vec<Diff>) -> Result<()> {
+    pub fn setup_environment(
+        &self,
+        runner: &dyn CommandRunner,
+        options: &ApplyOptions,
+        mut journal: Journal,
+        diffs: &[Diff],
+    ) -> Result<()> {
         let output = Command::new("df")
-            .args(&["-BG", "--output=size", "/"])
+            .args(["-BG", "--output=size", "/"])
             .output()
             .context("Failed to check disk size")?;
 
@@ -140,107 +1294,226 @@ impl Configuration {
             return Err(Error::msg("Insufficient disk size: >= 7GB required"));
         }
 
-        let steps = self.diffs_to_setup_steps(diffs);
+        let steps = self.diffs_to_setup_steps(diffs)?;
 
-        if dry_run {
+        if options.dry_run {
             println!("{}", "=== Dry Run: Environment Setup ===".yellow().bold());
+            println!(
+                "Resolved order: {}",
+                steps
+                    .iter()
+                    .map(|step| step.name())
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+                    .cyan()
+            );
             println!("Steps to be executed ({} total):", steps.len());
             for (i, step) in steps.iter().enumerate() {
-                println!("\n=> Step {}:\n{}", i + 1, step.format_dry_run());
+                let skipped = match journal.is_completed(step.name()) {
+                    true => " (completed in the interrupted run, skipped)",
+                    false => "",
+                };
+                println!(
+                    "\n=> Step {}{}:\n{}",
+                    i + 1,
+                    skipped,
+                    step.format_dry_run(runner)
+                );
             }
             println!("{}", "=== Dry Run Complete ===".yellow().bold());
             return Ok(());
         }
 
-        for step in steps {
-            step.run()?;
+        // The lock only records the sections whose steps succeeded, so an
+        // interrupted run is picked up by the next diff.
+        let mut lock = Lock::load()?;
+        lock.config.network = self.network.clone();
+
+        let (skipped, steps): (Vec<_>, Vec<_>) = steps
+            .into_iter()
+            .partition(|step| journal.is_completed(step.name()));
+        for step in skipped {
+            println!(
+                "{} Skipping {}, completed in the interrupted run.",
+                "=>".green(),
+                step.name().green()
+            );
+        }
+
+        // A section is copied to the lock once all of its steps completed.
+        let mut pending_sections: HashMap<&str, usize> = HashMap::new();
+        for step in &steps {
+            *pending_sections.entry(step.section()).or_default() += 1;
         }
 
-        self.write_lock_file()?;
+        let runner = EnvRunner::new(
+            runner,
+            self.env_vars(),
+            self.path.clone().unwrap_or_default(),
+        );
+        let runner = &runner;
+        let installers = self.installers.clone().unwrap_or_default();
+        let jobs = options.jobs.max(1);
+        run_steps(
+            &steps,
+            jobs,
+            |step| {
+                let policy = self.retry_policy(step.name(), &options.retry);
+                let runner = RetryRunner::new(runner, policy);
+                match (step.is_interactive(), jobs) {
+                    (true, _) => step.run(&InteractiveRunner::new(&runner), &installers),
+                    (false, 1) => step.run(&runner, &installers),
+                    (false, _) => step.run(&LabeledRunner::new(&runner, step.name()), &installers),
+                }
+            },
+            |step, result| {
+                if let Err(err) = result {
+                    journal.failed = Some(step.name().to_string());
+                    journal.save()?;
+                    return Err(err.context(format!(
+                        "Step {} failed. Run `oh-my-droid setup --resume` to continue from it",
+                        step.name()
+                    )));
+                }
+
+                journal.complete(step.name());
+                journal.save()?;
+                let pending = pending_sections.entry(step.section()).or_default();
+                *pending = pending.saturating_sub(1);
+                if *pending == 0 {
+                    lock.apply_section(self, step.section(), runner)?;
+                }
+                lock.save()
+            },
+        )?;
+
+        lock.complete(self);
+        lock.save()?;
+        Journal::clear()?;
 
         println!("{}", "Environment setup completed successfully 🎉".green());
         println!("You can now open a new terminal to see the changes.");
         println!(
             "Or run {} to apply the changes to the current terminal session.",
-            "source ~/.bashrc".green()
+            format!("source ~/{}", self.shell().rc_file()).green()
         );
 
         Ok(())
     }
 
-    pub fn write_lock_file(&self) -> Result<()> {
-        let home_dir = dirs::home_dir().context("Failed to get home directory")?;
-        let config_path = home_dir.join(".oh-my-droid/lock.toml");
-
-        fs::create_dir_all(
-            config_path
-                .parent()
-                .context("Failed to get parent directory of lock file")?,
-        )?;
-
-        let mut file = File::create(&config_path).context("Failed to create lock file")?;
-        file.write_all(
-            toml::to_string(&self)
-                .context("Failed to serialize config")?
-                .as_bytes(),
-        )
-        .context("Failed to write lock file")?;
-
-        Ok(())
+    /// Copies the section applied by the setup step `step` from `other`.
+    pub fn apply_section(&mut self, other: &Configuration, step: &str) {
+        match step {
+            "apt-get" => {
+                self.apt_get = other.apt_get.clone();
+                self.apt = other.apt.clone();
+            }
+            "apt" => self.apt = other.apt.clone(),
+            "pkgx" => self.pkgx = other.pkgx.clone(),
+            "curl" => self.curl = other.curl.clone(),
+            "mise" => self.mise = other.mise.clone(),
+            "ble.sh" => self.blesh = other.blesh.clone(),
+            "shell-init" => {
+                self.alias = other.alias.clone();
+                self.functions = other.functions.clone();
+                self.env = other.env.clone();
+                self.path = other.path.clone();
+                self.neofetch = other.neofetch;
+            }
+            "nix" => self.nix = other.nix.clone(),
+            "stow" => self.stow = other.stow.clone(),
+            "zoxide" => self.zoxide = other.zoxide.clone(),
+            "ssh" => self.ssh = other.ssh.clone(),
+            "tailscale" => self.tailscale = other.tailscale,
+            "doppler" => self.doppler = other.doppler,
+            "npm" => self.npm = other.npm.clone(),
+            "shell" => self.shell = other.shell.clone(),
+            // the prompt is set up by the step of its provider
+            "oh_my_posh" => {
+                self.oh_my_posh = other.oh_my_posh.clone();
+                self.prompt = other.prompt.clone();
+            }
+            "starship" => self.prompt = other.prompt.clone(),
+            _ => {}
+        }
     }
 
-    pub fn load_lock_file() -> Result<Configuration> {
-        let home_dir = dirs::home_dir().context("Failed to get home directory")?;
-        let config_path = home_dir.join(".oh-my-droid/lock.toml");
-
-        let toml_str = fs::read_to_string(&config_path).context("Failed to read lock file")?;
-        let loaded_config: Configuration =
-            toml::from_str(&toml_str).context("Failed to parse lock file")?;
-
-        Ok(loaded_config)
+    /// Turns diffs into setup steps, each step appearing once, ordered so
+    /// that every step runs after the steps it depends on. The shell init
+    /// script is rewritten on every run.
+    pub fn diffs_to_setup_steps<'a>(&'a self, diffs: &[Diff]) -> Result<Vec<SetupStep<'a>>> {
+        let mut steps = vec![SetupStep::Paths, SetupStep::ShellInit(self)];
+        self.collect_setup_steps(&mut steps, diffs);
+        sort_steps(steps)
     }
 
-    pub fn diffs_to_setup_steps<'a>(&'a self, diffs: Vec<Diff>) -> Vec<SetupStep<'a>> {
-        let mut steps = Vec::new();
-
-        steps.push(SetupStep::Paths);
-
+    fn collect_setup_steps<'a>(&'a self, steps: &mut Vec<SetupStep<'a>>, diffs: &[Diff]) {
         for diff in diffs {
             match diff {
                 Diff::Added(parent, _child, _value) => {
-                    self.add_setup_step_for_parent(&mut steps, &parent);
+                    self.add_setup_step_for_parent(steps, parent);
                 }
-                Diff::Changed(parent, _child, _old, _new) => {
-                    self.add_setup_step_for_parent(&mut steps, &parent);
+                Diff::Changed(parent, _child, old, new) => {
+                    if old == "true" && new == "false" {
+                        self.add_teardown_step(steps, parent, old);
+                    }
+                    self.add_setup_step_for_parent(steps, parent);
                 }
                 Diff::Nested(parent, nested_diffs) => {
-                    self.add_setup_step_for_parent(&mut steps, &parent);
-                    let nested_steps = self.diffs_to_setup_steps(nested_diffs);
-                    steps.extend(
-                        nested_steps
-                            .into_iter()
-                            .filter(|step| !matches!(step, SetupStep::Paths)),
-                    );
+                    self.add_setup_step_for_parent(steps, parent);
+                    self.collect_setup_steps(steps, nested_diffs);
                 }
-                Diff::Removed(_parent, _child, _value) => {
-                    // For removed items, we typically don't need to do anything
-                    // as the setup is additive, but you could add cleanup logic here if needed
+                Diff::Removed(parent, child, value) => {
+                    let item = if child.is_empty() { value } else { child };
+                    self.add_teardown_step(steps, parent, item);
+                    if parent == "prompt" {
+                        // the prompt falls back to [oh_my_posh], if any
+                        self.add_setup_step_for_parent(steps, parent);
+                    }
                 }
             }
         }
+    }
 
-        steps.dedup_by(|a, b| std::mem::discriminant(a) == std::mem::discriminant(b));
-
-        steps
+    /// Adds the removal of `item` from the section `parent`, merged into
+    /// the section's existing removal step.
+    fn add_teardown_step(&self, steps: &mut Vec<SetupStep>, parent: &str, item: &str) {
+        let still_configured = match parent {
+            // a removed key of these sections is only undone with the whole section
+            "stow" => self.stow.is_some(),
+            // a built-in repository is kept unless it is disabled
+            "apt" => self.apt_repositories().iter().any(|(name, _)| name == item),
+            // a removed boolean only needs undoing when it was enabled
+            "tailscale" | "doppler" => item != "true",
+            "zoxide" => self.zoxide.as_ref().is_some_and(ZoxideConfig::enabled),
+            // the Home Manager generations are kept, home-manager owns them
+            "nix" => item == HOME_MANAGER,
+            // ble.sh is only removed from a prefix it is no longer installed to
+            "ble.sh" => self
+                .blesh
+                .as_ref()
+                .is_some_and(|blesh| blesh.enabled() && blesh.prefix() == item),
+            _ => false,
+        };
+        if still_configured {
+            return;
+        }
+        let Some(teardown) = Teardown::for_section(parent, item) else {
+            return;
+        };
+        match steps.iter_mut().find_map(|step| match step {
+            SetupStep::Remove(existing) if existing.name() == teardown.name() => Some(existing),
+            _ => None,
+        }) {
+            Some(existing) => existing.merge(teardown),
+            None => steps.push(SetupStep::Remove(teardown)),
+        }
     }
 
     fn add_setup_step_for_parent<'a>(&'a self, steps: &mut Vec<SetupStep<'a>>, parent: &str) {
         match parent {
-            "apt-get" => {
-                if let Some(apt_packages) = &self.apt_get {
-                    steps.push(SetupStep::AptGet(apt_packages));
-                }
-            }
+            // changes to the repositories also go through apt-get
+            "apt-get" | "apt" => steps.push(SetupStep::AptGet(self)),
             "pkgx" => {
                 if let Some(pkgx_packages) = &self.pkgx {
                     steps.push(SetupStep::Pkgx(pkgx_packages));
@@ -257,8 +1530,8 @@ impl Configuration {
                 }
             }
             "ble.sh" => {
-                if let Some(blesh_enabled) = self.blesh {
-                    steps.push(SetupStep::BleSh(blesh_enabled));
+                if let Some(blesh) = &self.blesh {
+                    steps.push(SetupStep::BleSh(blesh));
                 }
             }
             "nix" => {
@@ -271,20 +1544,9 @@ impl Configuration {
                     steps.push(SetupStep::Stow(stow_configs));
                 }
             }
-            "oh_my_posh" => {
-                if let Some(oh_my_posh) = &self.oh_my_posh {
-                    let theme = oh_my_posh.theme.as_deref().unwrap_or("tokyonight_storm");
-                    steps.push(SetupStep::OhMyPosh(theme));
-                }
-            }
             "zoxide" => {
-                if let Some(zoxide_enabled) = self.zoxide {
-                    steps.push(SetupStep::Zoxide(zoxide_enabled));
-                }
-            }
-            "alias" => {
-                if let Some(aliases) = &self.alias {
-                    steps.push(SetupStep::Alias(aliases));
+                if let Some(zoxide) = &self.zoxide {
+                    steps.push(SetupStep::Zoxide(zoxide));
                 }
             }
             "ssh" => {
@@ -297,11 +1559,6 @@ impl Configuration {
                     steps.push(SetupStep::Tailscale(tailscale_enabled));
                 }
             }
-            "neofetch" => {
-                if let Some(neofetch_enabled) = self.neofetch {
-                    steps.push(SetupStep::Neofetch(neofetch_enabled));
-                }
-            }
             "doppler" => {
                 if let Some(doppler_enabled) = self.doppler {
                     steps.push(SetupStep::Doppler(doppler_enabled));
@@ -312,11 +1569,109 @@ impl Configuration {
                     steps.push(SetupStep::Npm(npm_packages));
                 }
             }
+            "shell" => {
+                if let Some(shell) = &self.shell {
+                    steps.push(SetupStep::Shell(shell));
+                }
+            }
+            "oh_my_posh" | "prompt" => match (self.prompt_provider(), &self.prompt) {
+                (Some(PromptProvider::OhMyPosh), _) => {
+                    steps.push(SetupStep::OhMyPosh(
+                        self.oh_my_posh.as_ref().unwrap_or(&DEFAULT_OH_MY_POSH),
+                    ));
+                }
+                (Some(PromptProvider::Starship), Some(prompt)) => {
+                    steps.push(SetupStep::Starship(prompt));
+                }
+                _ => {}
+            },
+            // only rendered into the shell init script, which is rewritten on every run
+            "alias" | "functions" | "env" | "path" | "neofetch" => {}
             _ => {} // Ignore unknown configuration keys
         }
     }
 }
 
+/// The `[nix]` entry holding the flake reference of a Home Manager
+/// configuration, e.g. `home-manager = "github:org/home#phone"`.
+pub const HOME_MANAGER: &str = "home-manager";
+
+/// The flake reference of a Home Manager configuration, with the
+/// `tangled:` shorthand, https URLs and `@ref` suffixes of remote
+/// configurations turned into git flake references.
+pub fn home_manager_flake(value: &str) -> String {
+    let (repo, attr) = match value.split_once('#') {
+        Some((repo, attr)) => (repo, Some(attr)),
+        None => (value, None),
+    };
+    let remote = ["github:", "tangled:", "https://"]
+        .iter()
+        .any(|prefix| repo.starts_with(prefix));
+    // github: is a flake reference already, unless it names a version
+    if !remote || (repo.starts_with("github:") && extract_version(repo).1.is_none()) {
+        return value.to_string();
+    }
+
+    let url = parse_config_path(repo).unwrap_or_else(|_| repo.to_string());
+    let (url, version) = extract_version(&url);
+    let mut flake = format!("git+{url}");
+    if let Some(version) = version {
+        flake.push_str(&format!("?ref={version}"));
+    }
+    if let Some(attr) = attr {
+        flake.push_str(&format!("#{attr}"));
+    }
+    flake
+}
+
+/// What `nix profile install` installs for the `[nix]` entry `name`:
+/// `nixpkgs#name` for "latest", else the `nixpkgs#pkg` or flake reference
+/// given as value.
+pub fn nix_installable(name: &str, value: &str) -> String {
+    match value {
+        "latest" => format!("nixpkgs#{name}"),
+        _ => value.to_string(),
+    }
+}
+
+/// Whether `name` can be used as a file name in the apt directories.
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Whether `value` is a sha256 in hex.
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether `name` can be used as a shell variable name.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Function names may also contain dashes, e.g. `git-clean`.
+fn is_function_name(name: &str) -> bool {
+    is_identifier(&name.replace('-', "_"))
+}
+
+/// Alias names may also contain punctuation that is not special to the
+/// shell, e.g. `..` or `g.`.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-+:@%,".contains(c))
+}
+
 impl Default for Configuration {
     fn default() -> Self {
         Configuration {
@@ -340,9 +1695,10 @@ impl Default for Configuration {
                     "stow",
                 ]
                 .into_iter()
-                .map(String::from)
+                .map(AptPackage::from)
                 .collect(),
             ),
+            apt: None,
             pkgx: Some(HashMap::from([
                 ("tig".into(), "latest".into()),
                 ("rg".into(), "latest".into()),
@@ -364,18 +1720,22 @@ impl Default for Configuration {
                 ("deno".into(), "https://deno.land/install.sh".into()),
                 ("pkgx".into(), "https://pkgx.sh".into()),
             ])),
+            installers: None,
             mise: Some(HashMap::from([("node".into(), "latest".into())])),
-            blesh: Some(true),
-            zoxide: Some(true),
+            blesh: Some(BleShConfig::Enabled(true)),
+            zoxide: Some(ZoxideConfig::Enabled(true)),
             nix: None,
             stow: Some(HashMap::from([(
                 "git".into(),
                 "github:tsirysndr/android-dotfiles".into(),
             )])),
+            prompt: None,
             oh_my_posh: Some(OhMyPosh {
                 theme: Some("tokyonight_storm".into()),
+                ..Default::default()
             }),
             alias: Some(HashMap::from([("ls".into(), "eza -lh".into())])),
+            functions: None,
             tailscale: Some(false),
             ssh: Some(SshConfig {
                 port: Some(8022),
@@ -384,6 +1744,748 @@ impl Default for Configuration {
             neofetch: Some(true),
             doppler: Some(false),
             npm: None,
+            shell: None,
+            env: None,
+            path: None,
+            network: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::compare_configurations;
+
+    use super::*;
+
+    #[test]
+    fn test_retry_policy_precedence() {
+        let cfg: Configuration = toml::from_str(
+            r#"
+            [network]
+            timeout = 300
+            retries = 3
+
+            [network.steps.curl]
+            timeout = 900
+            backoff = 1
+            "#,
+        )
+        .unwrap();
+
+        let policy = cfg.retry_policy("curl", &RetryConfig::default());
+        assert_eq!(policy.timeout, Some(Duration::from_secs(900)));
+        assert_eq!(policy.retries, 3);
+        assert_eq!(policy.backoff, Duration::from_secs(1));
+
+        let policy = cfg.retry_policy("apt-get", &RetryConfig::default());
+        assert_eq!(policy.timeout, Some(Duration::from_secs(300)));
+        assert_eq!(policy.backoff, RetryPolicy::default().backoff);
+
+        let overrides = RetryConfig {
+            timeout: None,
+            retries: Some(0),
+            backoff: None,
+        };
+        let policy = cfg.retry_policy("curl", &overrides);
+        assert_eq!(policy.timeout, Some(Duration::from_secs(900)));
+        assert_eq!(policy.retries, 0);
+    }
+
+    #[test]
+    fn test_apply_section_copies_only_completed_steps() {
+        let new = Configuration::default();
+        let mut lock = Configuration::empty();
+        lock.apply_section(&new, "apt-get");
+        lock.apply_section(&new, "ble.sh");
+        lock.apply_section(&new, "paths");
+
+        assert_eq!(lock.apt_get, new.apt_get);
+        assert_eq!(lock.blesh, Some(BleShConfig::Enabled(true)));
+        assert!(lock.pkgx.is_none());
+        assert!(lock.curl.is_none());
+        assert!(lock.stow.is_none());
+    }
+
+    #[test]
+    fn test_failed_oh_my_posh_step_is_retried() {
+        let cfg: Configuration = toml::from_str("[oh_my_posh]\ntheme = \"atomic\"").unwrap();
+        let mut lock = Configuration::empty();
+        let mut completed = Vec::new();
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        let result = run_steps(
+            &steps,
+            1,
+            |step| match step {
+                SetupStep::OhMyPosh(_) => Err(anyhow::anyhow!("unknown theme")),
+                _ => Ok(()),
+            },
+            |step, result| {
+                result?;
+                completed.push(step.name());
+                lock.apply_section(&cfg, step.section());
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        assert!(completed.contains(&"shell-init"));
+        assert!(lock.oh_my_posh.is_none());
+
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "oh_my_posh"));
+    }
+
+    #[test]
+    fn test_diffs_to_setup_steps_respects_dependencies() {
+        let cfg = Configuration {
+            npm: Some(HashMap::from([("pnpm".into(), "latest".into())])),
+            ..Default::default()
+        };
+        let diffs = compare_configurations(&Configuration::empty(), &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        let names = steps.iter().map(|step| step.name()).collect::<Vec<_>>();
+        let position = |name: &str| names.iter().position(|n| *n == name).unwrap();
+
+        assert_eq!(names[0], "paths");
+        assert!(position("apt-get") < position("curl"));
+        assert!(position("apt-get") < position("stow"));
+        assert!(position("apt-get") < position("mise"));
+        assert!(position("curl") < position("pkgx"));
+        assert!(position("shell-init") < position("npm"));
+        assert!(position("mise") < position("npm"));
+        assert!(position("pkgx") < position("npm"));
+
+        let mut unique = names.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), names.len());
+    }
+
+    #[test]
+    fn test_retry_policy_defaults() {
+        let policy = Configuration::empty().retry_policy("stow", &RetryConfig::default());
+        assert_eq!(policy, RetryPolicy::default());
+    }
+
+    #[test]
+    fn test_removed_entries_become_teardown_steps() {
+        let lock = Configuration {
+            apt_get: Some(vec!["git".into(), "tmux".into(), "httpie".into()]),
+            npm: Some(HashMap::from([("pnpm".into(), "latest".into())])),
+            zoxide: Some(ZoxideConfig::Enabled(true)),
+            neofetch: Some(false),
+            ..Configuration::empty()
+        };
+        let cfg = Configuration {
+            apt_get: Some(vec!["git".into()]),
+            zoxide: Some(ZoxideConfig::Enabled(false)),
+            ..Configuration::empty()
+        };
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        let removals = steps
+            .iter()
+            .filter_map(|step| match step {
+                SetupStep::Remove(teardown) => Some(teardown.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(removals.len(), 3);
+        assert!(removals.contains(&Teardown::Npm(vec!["pnpm".into()])));
+        assert!(removals.contains(&Teardown::Zoxide));
+        let Some(Teardown::AptGet(mut pkgs)) =
+            removals.iter().find(|t| t.section() == "apt-get").cloned()
+        else {
+            panic!("apt-get packages are not removed");
+        };
+        pkgs.sort();
+        assert_eq!(pkgs, vec!["httpie", "tmux"]);
+    }
+
+    #[test]
+    fn test_parse_shell_setting() {
+        let cfg: Configuration = toml::from_str("shell = \"zsh\"").unwrap();
+        assert_eq!(cfg.shell, Some(ShellConfig::Name(Shell::Zsh)));
+        assert_eq!(cfg.shell(), Shell::Zsh);
+
+        let cfg: Configuration = toml::from_str("[shell]\nname = \"fish\"\nchsh = true").unwrap();
+        let shell = cfg.shell.unwrap();
+        assert_eq!(shell.name(), Some(Shell::Fish));
+        assert!(shell.chsh());
+        assert!(shell.manage_rc());
+
+        let cfg: Configuration = toml::from_str("[shell]\nmanage_rc = false").unwrap();
+        let shell = cfg.shell.clone().unwrap();
+        assert_eq!(shell.name(), None);
+        assert!(!shell.manage_rc());
+        assert_eq!(shell.to_string(), "detected, rc file not managed");
+
+        assert!(toml::from_str::<Configuration>("shell = \"tcsh\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_alias_and_function_names() {
+        let cfg = Configuration {
+            alias: Some(HashMap::from([
+                ("..".into(), "cd ..".into()),
+                ("ls".into(), "eza -lh".into()),
+            ])),
+            functions: Some(HashMap::from([(
+                "git-clean".into(),
+                "git clean -fd".into(),
+            )])),
+            ..Configuration::empty()
+        };
+        assert!(cfg.validate().is_ok());
+
+        for name in ["rm -rf", "a=b", "x'y", "$(id)", ""] {
+            let cfg = Configuration {
+                alias: Some(HashMap::from([(name.into(), "true".into())])),
+                ..Configuration::empty()
+            };
+            assert!(
+                cfg.validate().is_err(),
+                "alias {:?} should be invalid",
+                name
+            );
+        }
+
+        let cfg = Configuration {
+            functions: Some(HashMap::from([("1up".into(), "true".into())])),
+            ..Configuration::empty()
+        };
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_env_names() {
+        let cfg = Configuration {
+            env: Some(HashMap::from([("EDITOR".into(), "nvim".into())])),
+            ..Configuration::empty()
+        };
+        assert!(cfg.validate().is_ok());
+
+        let cfg = Configuration {
+            env: Some(HashMap::from([("MY-VAR".into(), "1".into())])),
+            ..Configuration::empty()
+        };
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let cfg = |entries: &[(&str, &str)]| Configuration {
+            alias: Some(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            ..Configuration::empty()
+        };
+        let fingerprint = cfg(&[("ls", "eza -lh"), ("g", "git"), ("v", "nvim")])
+            .fingerprint()
+            .unwrap();
+        assert_eq!(fingerprint.len(), 16);
+        assert_eq!(
+            cfg(&[("v", "nvim"), ("g", "git"), ("ls", "eza -lh")])
+                .fingerprint()
+                .unwrap(),
+            fingerprint
+        );
+        assert_ne!(
+            cfg(&[("ls", "eza -la"), ("g", "git"), ("v", "nvim")])
+                .fingerprint()
+                .unwrap(),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn test_reordered_path_is_a_change() {
+        let old = Configuration {
+            path: Some(vec!["~/go/bin".into(), "~/.cargo/bin".into()]),
+            ..Configuration::empty()
+        };
+        assert!(compare_configurations(&old, &old.clone()).is_empty());
+
+        let new = Configuration {
+            path: Some(vec!["~/.cargo/bin".into(), "~/go/bin".into()]),
+            ..Configuration::empty()
+        };
+        let diffs = compare_configurations(&old, &new);
+        assert_eq!(diffs.len(), 1);
+        assert!(matches!(
+            &diffs[0],
+            Diff::Changed(parent, _, old, new)
+                if parent == "path" && old == "~/go/bin, ~/.cargo/bin" && new == "~/.cargo/bin, ~/go/bin"
+        ));
+        let steps = new.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(
+            steps
+                .iter()
+                .any(|step| matches!(step, SetupStep::ShellInit(_)))
+        );
+    }
+
+    #[test]
+    fn test_oh_my_posh_config_file() {
+        let omp = OhMyPosh::default();
+        assert_eq!(
+            omp.config_file(),
+            "~/.cache/oh-my-posh/themes/tokyonight_storm.omp.json"
+        );
+
+        let cfg: Configuration = toml::from_str(
+            "[oh_my_posh]\ntheme = \"atomic\"\nconfig_url = \"https://example.com/prompt.yaml?raw=1\"",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        let omp = cfg.oh_my_posh.clone().unwrap();
+        assert_eq!(omp.config_file(), "~/.oh-my-droid/oh-my-posh.omp.yaml");
+
+        let omp = OhMyPosh {
+            config_path: Some("~/.config/prompt.toml".into()),
+            ..omp
+        };
+        assert_eq!(omp.config_file(), "~/.config/prompt.toml");
+
+        let cfg: Configuration =
+            toml::from_str("[oh_my_posh]\nconfig_url = \"file:///etc/passwd\"").unwrap();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_oh_my_posh_changes_run_its_step() {
+        let lock = Configuration {
+            oh_my_posh: Some(OhMyPosh::default()),
+            ..Configuration::empty()
+        };
+        let cfg = Configuration {
+            oh_my_posh: Some(OhMyPosh {
+                config_url: Some("https://example.com/prompt.omp.json".into()),
+                ..Default::default()
+            }),
+            ..Configuration::empty()
+        };
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "oh_my_posh"));
+
+        let diffs = compare_configurations(&Configuration::empty(), &lock);
+        let steps = lock.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "oh_my_posh"));
+    }
+
+    #[test]
+    fn test_switch_prompt_provider() {
+        let lock: Configuration = toml::from_str("[oh_my_posh]\ntheme = \"atomic\"").unwrap();
+        assert_eq!(lock.prompt_provider(), Some(PromptProvider::OhMyPosh));
+
+        let cfg: Configuration = toml::from_str(
+            "[oh_my_posh]\ntheme = \"atomic\"\n[prompt]\nprovider = \"starship\"\n[prompt.config]\nadd_newline = false",
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.prompt_provider(), Some(PromptProvider::Starship));
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        let names = steps.iter().map(|step| step.name()).collect::<Vec<_>>();
+        assert!(names.contains(&"starship"));
+        assert!(!names.contains(&"oh_my_posh"));
+
+        // dropping [prompt] switches back to oh-my-posh
+        let diffs = compare_configurations(&cfg, &lock);
+        let steps = lock.diffs_to_setup_steps(&diffs).unwrap();
+        let names = steps.iter().map(|step| step.name()).collect::<Vec<_>>();
+        assert!(names.contains(&"oh_my_posh"));
+        assert!(!names.contains(&"starship"));
+    }
+
+    #[test]
+    fn test_failed_starship_step_is_retried() {
+        let cfg: Configuration =
+            toml::from_str("[prompt]\nprovider = \"starship\"\npreset = \"tokyo-night\"").unwrap();
+        let mut lock = Configuration::empty();
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        let result = run_steps(
+            &steps,
+            1,
+            |step| match step {
+                SetupStep::Starship(_) => Err(anyhow::anyhow!("unknown preset")),
+                _ => Ok(()),
+            },
+            |step, result| {
+                result?;
+                lock.apply_section(&cfg, step.section());
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        assert!(lock.prompt.is_none());
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "starship"));
+
+        // the oh-my-posh step records the prompt it sets up
+        let cfg: Configuration = toml::from_str("[prompt]\nprovider = \"oh-my-posh\"").unwrap();
+        let mut lock = Configuration::empty();
+        lock.apply_section(&cfg, "shell-init");
+        assert!(lock.prompt.is_none());
+        lock.apply_section(&cfg, "oh_my_posh");
+        assert_eq!(lock.prompt, cfg.prompt);
+
+        // dropping [prompt] falls back to oh-my-posh, whose step records it
+        let old: Configuration =
+            toml::from_str("[oh_my_posh]\ntheme = \"atomic\"\n[prompt]\nprovider = \"starship\"")
+                .unwrap();
+        let cfg: Configuration = toml::from_str("[oh_my_posh]\ntheme = \"atomic\"").unwrap();
+        let mut lock = old.clone();
+        for step in cfg
+            .diffs_to_setup_steps(&compare_configurations(&old, &cfg))
+            .unwrap()
+        {
+            lock.apply_section(&cfg, step.section());
+        }
+        assert!(lock.prompt.is_none());
+    }
+
+    #[test]
+    fn test_validate_prompt() {
+        for (toml, valid) in [
+            ("provider = \"starship\"\npreset = \"tokyo-night\"", true),
+            ("provider = \"oh-my-posh\"", true),
+            ("provider = \"oh-my-posh\"\npreset = \"tokyo-night\"", false),
+            ("provider = \"starship\"\npreset = \"x; rm -rf ~\"", false),
+            (
+                "provider = \"starship\"\npreset = \"pure-preset\"\nconfig = { add_newline = false }",
+                false,
+            ),
+        ] {
+            let cfg: Configuration = toml::from_str(&format!("[prompt]\n{toml}")).unwrap();
+            assert_eq!(cfg.validate().is_ok(), valid, "{toml}");
+        }
+        assert!(toml::from_str::<Configuration>("[prompt]\nprovider = \"powerline\"").is_err());
+    }
+
+    #[test]
+    fn test_blesh_version_and_prefix_changes() {
+        let lock: Configuration = toml::from_str("\"ble.sh\" = true").unwrap();
+        let cfg: Configuration =
+            toml::from_str("[\"ble.sh\"]\nref = \"v0.4.0-devel3\"\nprefix = \"~/opt\"").unwrap();
+        let blesh = cfg.blesh.clone().unwrap();
+        assert_eq!(blesh.version(), "v0.4.0-devel3");
+        assert_eq!(blesh.script(), "~/opt/share/blesh/ble.sh");
+
+        let teardowns = |steps: &[SetupStep]| {
+            steps
+                .iter()
+                .filter_map(|step| match step {
+                    SetupStep::Remove(teardown) => Some(teardown.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // a new version and prefix: reinstall, and remove it from the old prefix
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "ble.sh"));
+        assert_eq!(
+            teardowns(&steps),
+            vec![Teardown::BleSh(BleShConfig::DEFAULT_PREFIX.into())]
+        );
+
+        // disabling only removes it
+        let disabled: Configuration = toml::from_str("\"ble.sh\" = false").unwrap();
+        let diffs = compare_configurations(&cfg, &disabled);
+        let steps = disabled.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().all(|step| step.name() != "ble.sh"));
+        assert_eq!(teardowns(&steps), vec![Teardown::BleSh("~/opt".into())]);
+
+        let unchanged: Configuration =
+            toml::from_str("[\"ble.sh\"]\nversion = \"master\"").unwrap();
+        assert!(compare_configurations(&lock, &unchanged).is_empty());
+    }
+
+    #[test]
+    fn test_zoxide_table() {
+        let lock: Configuration = toml::from_str("zoxide = true").unwrap();
+        let cfg: Configuration =
+            toml::from_str("[zoxide]\nversion = \"0.9.8\"\ncmd = \"cd\"\nhook = \"prompt\"")
+                .unwrap();
+        assert!(cfg.validate().is_ok());
+        let zoxide = cfg.zoxide.clone().unwrap();
+        assert_eq!(zoxide.init_args(), "--cmd cd --hook prompt");
+        assert_eq!(zoxide.to_string(), "0.9.8, --cmd cd --hook prompt");
+
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "zoxide"));
+        assert!(steps.iter().all(|step| step.name() != "remove:zoxide"));
+
+        let empty = Configuration::empty();
+        let diffs = compare_configurations(&cfg, &empty);
+        let steps = empty.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "remove:zoxide"));
+
+        let cfg: Configuration = toml::from_str("[zoxide]\ncmd = \"c d\"").unwrap();
+        assert!(cfg.validate().is_err());
+        assert!(toml::from_str::<Configuration>("[zoxide]\nhook = \"always\"").is_err());
+    }
+
+    #[test]
+    fn test_apt_repositories() {
+        let cfg: Configuration = toml::from_str(
+            r#"
+            [apt.repositories]
+            vscode = false
+
+            [apt.repositories.docker]
+            uri = "https://download.docker.com/linux/debian"
+            suite = "bookworm"
+            components = ["stable"]
+            key_url = "https://download.docker.com/linux/debian/gpg"
+            fingerprint = "9DC8 5822 9FC7 DD38 854A  E2D8 8D81 803C 0EBF CD88"
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        let names = cfg
+            .apt_repositories()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["docker", "mise"]);
+
+        let (_, docker) = &cfg.apt_repositories()[0];
+        assert_eq!(
+            docker.sources("/etc/apt/keyrings/docker.gpg"),
+            "# Managed by oh-my-droid, key fingerprint 9DC858229FC7DD38854AE2D88D81803C0EBFCD88\nTypes: deb\nURIs: https://download.docker.com/linux/debian\nSuites: bookworm\nComponents: stable\nSigned-By: /etc/apt/keyrings/docker.gpg\n"
+        );
+
+        // disabling a built-in repository removes it
+        let lock = Configuration::empty();
+        let diffs = compare_configurations(&lock, &cfg);
+        let steps = cfg.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "apt-get"));
+        let removals = steps
+            .iter()
+            .filter_map(|step| match step {
+                SetupStep::Remove(teardown) => Some(teardown.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            removals,
+            vec![Teardown::AptRepositories(vec!["vscode".into()])]
+        );
+
+        for repositories in [
+            "custom = true",
+            "\"../x\" = false",
+            "[apt.repositories.x]\nuri = \"https://x\"\nsuite = \"s\"\nkey_url = \"https://x/key\"\nfingerprint = \"abc\"",
+        ] {
+            let toml = match repositories.starts_with('[') {
+                true => repositories.to_string(),
+                false => format!("[apt.repositories]\n{repositories}"),
+            };
+            let cfg: Configuration = toml::from_str(&toml).unwrap();
+            assert!(cfg.validate().is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn test_apt_package_pins() {
+        let old: Configuration = toml::from_str(
+            r#"
+            apt-get = ["git", { name = "tmux", version = "3.3a-3" }]
+            "#,
+        )
+        .unwrap();
+        let new: Configuration = toml::from_str(
+            r#"
+            apt-get = [
+                "git",
+                { name = "tmux", version = "3.4-1", hold = true },
+                { name = "neovim", no-install-recommends = true },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert!(new.validate().is_ok());
+        let pkgs = new.apt_get.as_deref().unwrap();
+        assert_eq!(pkgs[0], AptPackage::from("git"));
+        assert_eq!(pkgs[1].to_string(), "tmux=3.4-1 (held)");
+        assert!(pkgs[2].no_install_recommends());
+        assert_eq!(
+            new.apt_preferences().unwrap(),
+            "# Managed by oh-my-droid\nPackage: tmux\nPin: version 3.4-1\nPin-Priority: 1001\n"
+        );
+        assert_eq!(Configuration::empty().apt_preferences(), None);
+
+        let diffs = compare_configurations(&old, &new);
+        assert!(diffs.iter().any(|diff| matches!(
+            diff,
+            Diff::Changed(parent, child, old, new)
+                if parent == "apt-get" && child == "tmux" && old == "tmux=3.3a-3" && new == "tmux=3.4-1 (held)"
+        )));
+        let steps = new.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "apt-get"));
+
+        let cfg: Configuration =
+            toml::from_str(r#"apt-get = [{ name = "tmux", version = "3.4 1" }]"#).unwrap();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_nix_packages() {
+        let cfg: Configuration = toml::from_str(
+            r#"
+            [nix]
+            ripgrep = "latest"
+            hello = "github:example/hello"
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(nix_installable("ripgrep", "latest"), "nixpkgs#ripgrep");
+        assert_eq!(nix_installable("rg", "nixpkgs#ripgrep"), "nixpkgs#ripgrep");
+
+        let removed = Configuration {
+            nix: Some(HashMap::from([("ripgrep".into(), "latest".into())])),
+            ..Configuration::empty()
+        };
+        let diffs = compare_configurations(&cfg, &removed);
+        let steps = removed.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| matches!(
+            step,
+            SetupStep::Remove(Teardown::Nix(names)) if names == &vec!["hello".to_string()]
+        )));
+
+        let cfg: Configuration = toml::from_str("[nix]\nripgrep = \"14.1\"").unwrap();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_home_manager_flake() {
+        for (value, expected) in [
+            ("github:org/home#phone", "github:org/home#phone"),
+            (
+                "github:org/home@v2#phone",
+                "git+https://github.com/org/home?ref=v2#phone",
+            ),
+            (
+                "tangled:@org.dev/home#phone",
+                "git+https://tangled.sh/@org.dev/home#phone",
+            ),
+            (
+                "https://git.example.com/home@main",
+                "git+https://git.example.com/home?ref=main",
+            ),
+            ("path:/home/user/home#phone", "path:/home/user/home#phone"),
+        ] {
+            assert_eq!(home_manager_flake(value), expected);
+        }
+
+        let cfg: Configuration = toml::from_str(
+            r#"
+            [nix]
+            home-manager = "github:org/home#phone"
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        // removing it keeps the applied configuration
+        let empty = Configuration::empty();
+        let diffs = compare_configurations(&cfg, &empty);
+        let steps = empty.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(!steps.iter().any(|step| step.section() == "nix"));
+
+        let cfg: Configuration = toml::from_str("[nix]\nhome-manager = \"latest\"").unwrap();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_builtin_installer_checksums() {
+        let sha256 = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d";
+        let cfg: Configuration = toml::from_str(&format!(
+            "tailscale = true\n[zoxide]\nversion = \"0.9.8\"\n[prompt]\nprovider = \"starship\"\n[installers]\nnix = \"{sha256}\""
+        ))
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        // the pinned zoxide release is verified by its own checksum
+        assert_eq!(cfg.builtin_installers(), vec!["starship", "tailscale"]);
+
+        for installers in [
+            format!("[installers]\nbun = \"{sha256}\""),
+            "[installers]\nnix = \"abc\"".to_string(),
+        ] {
+            let cfg: Configuration = toml::from_str(&installers).unwrap();
+            assert!(cfg.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_curl_installer_checksums() {
+        let old: Configuration = toml::from_str(
+            r#"
+            [curl]
+            bun = "https://bun.sh/install"
+            "#,
+        )
+        .unwrap();
+        let new: Configuration = toml::from_str(
+            r#"
+            [curl]
+            bun = { url = "https://bun.sh/install", sha256 = "3f2a7c1e9b4d6a8f0c2e4b6d8a0c2e4f6a8b0d2c4e6f8a0b2c4d6e8f0a2b4c6d" }
+            "#,
+        )
+        .unwrap();
+        assert!(new.validate().is_ok());
+        let bun = &new.curl.as_ref().unwrap()["bun"];
+        assert_eq!(bun.url(), "https://bun.sh/install");
+        assert!(bun.sha256().is_some());
+
+        // pinning the script runs the installer again
+        let diffs = compare_configurations(&old, &new);
+        assert_eq!(diffs.len(), 1);
+        let steps = new.diffs_to_setup_steps(&diffs).unwrap();
+        assert!(steps.iter().any(|step| step.name() == "curl"));
+
+        let cfg: Configuration =
+            toml::from_str("[curl]\nbun = { url = \"https://bun.sh/install\", sha256 = \"abc\" }")
+                .unwrap();
+        assert!(cfg.validate().is_err());
+
+        let cfg: Configuration = toml::from_str(
+            r#"
+            [curl.rustup]
+            url = "https://sh.rustup.rs"
+            args = ["-y"]
+            interpreter = "sh"
+            creates = "~/.cargo/bin/rustup"
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(
+            cfg.curl.as_ref().unwrap()["rustup"].to_string(),
+            "https://sh.rustup.rs -y (sh) (creates ~/.cargo/bin/rustup)"
+        );
+        for installer in [
+            "rustup = { url = \"https://sh.rustup.rs\", interpreter = \"zsh\" }",
+            "rustup = { url = \"https://sh.rustup.rs\", env = { \"A-B\" = \"1\" } }",
+        ] {
+            let cfg = toml::from_str::<Configuration>(&format!("[curl]\n{installer}"));
+            assert!(
+                cfg.is_err() || cfg.unwrap().validate().is_err(),
+                "{installer}"
+            );
         }
     }
 }
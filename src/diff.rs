@@ -1,7 +1,10 @@
 use owo_colors::OwoColorize;
 use std::{collections::HashMap, fmt};
 
-use crate::config::{Configuration, OhMyPosh, SshConfig};
+use crate::config::{
+    AptPackage, AptRepository, BleShConfig, Configuration, CurlInstaller, OhMyPosh, PromptConfig,
+    ShellConfig, SshConfig, ZoxideConfig,
+};
 
 #[derive(Debug)]
 pub enum Diff {
@@ -129,6 +132,23 @@ fn compare_hashmap(
     diffs
 }
 
+/// Compares the `path` entries, which are prepended first entry first, so
+/// reordering them is a change too.
+fn compare_path(old: &Option<Vec<String>>, new: &Option<Vec<String>>) -> Vec<Diff> {
+    let diffs = compare_vec("path", old, new);
+    match (old, new) {
+        (Some(old_vec), Some(new_vec)) if diffs.is_empty() && old_vec != new_vec => {
+            vec![Diff::Changed(
+                "path".to_string(),
+                "".to_string(),
+                old_vec.join(", "),
+                new_vec.join(", "),
+            )]
+        }
+        _ => diffs,
+    }
+}
+
 fn compare_vec(parent: &str, old: &Option<Vec<String>>, new: &Option<Vec<String>>) -> Vec<Diff> {
     let mut diffs = Vec::new();
     match (old, new) {
@@ -197,53 +217,246 @@ fn compare_bool(parent: &str, old: &Option<bool>, new: &Option<bool>) -> Vec<Dif
     }
 }
 
-fn compare_oh_my_posh(old: &Option<OhMyPosh>, new: &Option<OhMyPosh>) -> Vec<Diff> {
-    let mut diffs = Vec::new();
-    match (old, new) {
-        (None, Some(new_omp)) => {
-            if let Some(theme) = &new_omp.theme {
-                diffs.push(Diff::Added(
-                    "oh_my_posh".to_string(),
-                    "theme".to_string(),
-                    theme.clone(),
-                ));
-            }
-        }
-        (Some(old_omp), None) => {
-            if let Some(theme) = &old_omp.theme {
+/// Disabling ble.sh, or moving it to another prefix, removes it from the
+/// old prefix, which is the value of the `Removed` diff.
+fn compare_blesh(old: &Option<BleShConfig>, new: &Option<BleShConfig>) -> Vec<Diff> {
+    let enabled = |config: &Option<BleShConfig>| config.clone().filter(|c| c.enabled());
+    match (enabled(old), enabled(new)) {
+        (None, Some(new_blesh)) => vec![Diff::Added(
+            "ble.sh".to_string(),
+            "".to_string(),
+            new_blesh.to_string(),
+        )],
+        (Some(old_blesh), None) => vec![Diff::Removed(
+            "ble.sh".to_string(),
+            "".to_string(),
+            old_blesh.prefix().to_string(),
+        )],
+        (Some(old_blesh), Some(new_blesh))
+            if old_blesh.version() != new_blesh.version()
+                || old_blesh.prefix() != new_blesh.prefix() =>
+        {
+            let mut diffs = vec![Diff::Changed(
+                "ble.sh".to_string(),
+                "".to_string(),
+                old_blesh.to_string(),
+                new_blesh.to_string(),
+            )];
+            if old_blesh.prefix() != new_blesh.prefix() {
                 diffs.push(Diff::Removed(
-                    "oh_my_posh".to_string(),
-                    "theme".to_string(),
-                    theme.clone(),
+                    "ble.sh".to_string(),
+                    "".to_string(),
+                    old_blesh.prefix().to_string(),
                 ));
             }
+            diffs
         }
-        (Some(old_omp), Some(new_omp)) => match (&old_omp.theme, &new_omp.theme) {
-            (None, Some(new_theme)) => {
+        _ => vec![],
+    }
+}
+
+/// Compares packages by name, a package whose version or options changed
+/// is a [`Diff::Changed`]. Removals carry the package name.
+fn compare_apt_packages(old: &Option<Vec<AptPackage>>, new: &Option<Vec<AptPackage>>) -> Vec<Diff> {
+    let old_packages = old.as_deref().unwrap_or_default();
+    let new_packages = new.as_deref().unwrap_or_default();
+    let find = |packages: &[AptPackage], name: &str| {
+        packages
+            .iter()
+            .find(|package| package.name() == name)
+            .cloned()
+    };
+
+    let mut diffs = Vec::new();
+    for package in new_packages {
+        match find(old_packages, package.name()) {
+            None => diffs.push(Diff::Added(
+                "apt-get".to_string(),
+                "".to_string(),
+                package.to_string(),
+            )),
+            Some(old_package) if old_package != *package => diffs.push(Diff::Changed(
+                "apt-get".to_string(),
+                package.name().to_string(),
+                old_package.to_string(),
+                package.to_string(),
+            )),
+            _ => {}
+        }
+    }
+    for package in old_packages {
+        if find(new_packages, package.name()).is_none() {
+            diffs.push(Diff::Removed(
+                "apt-get".to_string(),
+                "".to_string(),
+                package.name().to_string(),
+            ));
+        }
+    }
+    diffs
+}
+
+/// Compares the repositories set up, including the built-in ones, by name.
+fn compare_apt_repositories(old: &Configuration, new: &Configuration) -> Vec<Diff> {
+    let old_repositories = old.apt_repositories();
+    let new_repositories = new.apt_repositories();
+    let find = |repositories: &[(String, AptRepository)], name: &str| {
+        repositories
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, repository)| repository.clone())
+    };
+
+    let mut diffs = Vec::new();
+    for (name, new_repository) in &new_repositories {
+        match find(&old_repositories, name) {
+            None => diffs.push(Diff::Added(
+                "apt".to_string(),
+                name.clone(),
+                new_repository.to_string(),
+            )),
+            Some(old_repository) if old_repository != *new_repository => diffs.push(Diff::Changed(
+                "apt".to_string(),
+                name.clone(),
+                old_repository.to_string(),
+                new_repository.to_string(),
+            )),
+            _ => {}
+        }
+    }
+    for (name, old_repository) in &old_repositories {
+        if find(&new_repositories, name).is_none() {
+            diffs.push(Diff::Removed(
+                "apt".to_string(),
+                name.clone(),
+                old_repository.to_string(),
+            ));
+        }
+    }
+
+    if !diffs.is_empty() {
+        vec![Diff::Nested("apt".to_string(), diffs)]
+    } else {
+        vec![]
+    }
+}
+
+/// Disabling zoxide is a removal, so that it gets uninstalled.
+fn compare_zoxide(old: &Option<ZoxideConfig>, new: &Option<ZoxideConfig>) -> Vec<Diff> {
+    let enabled = |config: &Option<ZoxideConfig>| config.clone().filter(|c| c.enabled());
+    match (enabled(old), enabled(new)) {
+        (None, Some(new_zoxide)) => vec![Diff::Added(
+            "zoxide".to_string(),
+            "".to_string(),
+            new_zoxide.to_string(),
+        )],
+        (Some(old_zoxide), None) => vec![Diff::Removed(
+            "zoxide".to_string(),
+            "".to_string(),
+            old_zoxide.to_string(),
+        )],
+        (Some(old_zoxide), Some(new_zoxide)) if old_zoxide != new_zoxide => vec![Diff::Changed(
+            "zoxide".to_string(),
+            "".to_string(),
+            old_zoxide.to_string(),
+            new_zoxide.to_string(),
+        )],
+        _ => vec![],
+    }
+}
+
+fn compare_shell(old: &Option<ShellConfig>, new: &Option<ShellConfig>) -> Vec<Diff> {
+    match (old, new) {
+        (None, Some(new_shell)) => vec![Diff::Added(
+            "shell".to_string(),
+            "".to_string(),
+            new_shell.to_string(),
+        )],
+        (Some(old_shell), None) => vec![Diff::Removed(
+            "shell".to_string(),
+            "".to_string(),
+            old_shell.to_string(),
+        )],
+        (Some(old_shell), Some(new_shell)) if old_shell != new_shell => vec![Diff::Changed(
+            "shell".to_string(),
+            "".to_string(),
+            old_shell.to_string(),
+            new_shell.to_string(),
+        )],
+        _ => vec![],
+    }
+}
+
+fn compare_prompt(old: &Option<PromptConfig>, new: &Option<PromptConfig>) -> Vec<Diff> {
+    match (old, new) {
+        (None, Some(new_prompt)) => vec![Diff::Added(
+            "prompt".to_string(),
+            "".to_string(),
+            new_prompt.to_string(),
+        )],
+        (Some(old_prompt), None) => vec![Diff::Removed(
+            "prompt".to_string(),
+            "".to_string(),
+            old_prompt.to_string(),
+        )],
+        (Some(old_prompt), Some(new_prompt)) if old_prompt != new_prompt => vec![Diff::Changed(
+            "prompt".to_string(),
+            "".to_string(),
+            old_prompt.to_string(),
+            new_prompt.to_string(),
+        )],
+        _ => vec![],
+    }
+}
+
+fn compare_oh_my_posh(old: &Option<OhMyPosh>, new: &Option<OhMyPosh>) -> Vec<Diff> {
+    let empty = OhMyPosh::default();
+    let (old_omp, new_omp) = match (old, new) {
+        (None, None) => return vec![],
+        (old, new) => (
+            old.as_ref().unwrap_or(&empty),
+            new.as_ref().unwrap_or(&empty),
+        ),
+    };
+    let mut diffs = Vec::new();
+    for (field, old_value, new_value) in [
+        ("theme", &old_omp.theme, &new_omp.theme),
+        ("config_path", &old_omp.config_path, &new_omp.config_path),
+        ("config_url", &old_omp.config_url, &new_omp.config_url),
+    ] {
+        match (old_value, new_value) {
+            (None, Some(new_value)) => {
                 diffs.push(Diff::Added(
                     "oh_my_posh".to_string(),
-                    "theme".to_string(),
-                    new_theme.clone(),
+                    field.to_string(),
+                    new_value.clone(),
                 ));
             }
-            (Some(old_theme), None) => {
+            (Some(old_value), None) => {
                 diffs.push(Diff::Removed(
                     "oh_my_posh".to_string(),
-                    "theme".to_string(),
-                    old_theme.clone(),
+                    field.to_string(),
+                    old_value.clone(),
                 ));
             }
-            (Some(old_theme), Some(new_theme)) if old_theme != new_theme => {
+            (Some(old_value), Some(new_value)) if old_value != new_value => {
                 diffs.push(Diff::Changed(
                     "oh_my_posh".to_string(),
-                    "theme".to_string(),
-                    old_theme.clone(),
-                    new_theme.clone(),
+                    field.to_string(),
+                    old_value.clone(),
+                    new_value.clone(),
                 ));
             }
             _ => {}
-        },
-        (None, None) => {}
+        }
+    }
+    if diffs.is_empty() && old.is_none() {
+        // an empty [oh_my_posh] table enables the default theme
+        diffs.push(Diff::Added(
+            "oh_my_posh".to_string(),
+            "theme".to_string(),
+            new_omp.theme().to_string(),
+        ));
     }
     if !diffs.is_empty() {
         vec![Diff::Nested("oh_my_posh".to_string(), diffs)]
@@ -343,6 +556,22 @@ fn compare_ssh_config(old: &Option<SshConfig>, new: &Option<SshConfig>) -> Vec<D
     }
 }
 
+/// Compares the installers by URL and checksum.
+fn compare_curl(
+    old: &Option<HashMap<String, CurlInstaller>>,
+    new: &Option<HashMap<String, CurlInstaller>>,
+) -> Vec<Diff> {
+    let described = |installers: &Option<HashMap<String, CurlInstaller>>| {
+        installers.as_ref().map(|installers| {
+            installers
+                .iter()
+                .map(|(name, installer)| (name.clone(), installer.to_string()))
+                .collect::<HashMap<_, _>>()
+        })
+    };
+    compare_hashmap("curl", &described(old), &described(new))
+}
+
 pub fn compare_configurations(old: &Configuration, new: &Configuration) -> Vec<Diff> {
     let mut diffs = Vec::new();
 
@@ -350,20 +579,26 @@ pub fn compare_configurations(old: &Configuration, new: &Configuration) -> Vec<D
     diffs.extend(compare_hashmap("mise", &old.mise, &new.mise));
     diffs.extend(compare_hashmap("nix", &old.nix, &new.nix));
     diffs.extend(compare_hashmap("pkgx", &old.pkgx, &new.pkgx));
-    diffs.extend(compare_hashmap("curl", &old.curl, &new.curl));
+    diffs.extend(compare_curl(&old.curl, &new.curl));
     diffs.extend(compare_hashmap("alias", &old.alias, &new.alias));
+    diffs.extend(compare_hashmap("functions", &old.functions, &new.functions));
     diffs.extend(compare_hashmap("npm", &old.npm, &new.npm));
+    diffs.extend(compare_hashmap("env", &old.env, &new.env));
 
-    diffs.extend(compare_vec("apt-get", &old.apt_get, &new.apt_get));
+    diffs.extend(compare_apt_packages(&old.apt_get, &new.apt_get));
+    diffs.extend(compare_apt_repositories(old, new));
+    diffs.extend(compare_path(&old.path, &new.path));
 
-    diffs.extend(compare_bool("blesh", &old.blesh, &new.blesh));
-    diffs.extend(compare_bool("zoxide", &old.zoxide, &new.zoxide));
+    diffs.extend(compare_blesh(&old.blesh, &new.blesh));
+    diffs.extend(compare_zoxide(&old.zoxide, &new.zoxide));
     diffs.extend(compare_bool("tailscale", &old.tailscale, &new.tailscale));
     diffs.extend(compare_bool("neofetch", &old.neofetch, &new.neofetch));
     diffs.extend(compare_bool("doppler", &old.doppler, &new.doppler));
 
+    diffs.extend(compare_prompt(&old.prompt, &new.prompt));
     diffs.extend(compare_oh_my_posh(&old.oh_my_posh, &new.oh_my_posh));
     diffs.extend(compare_ssh_config(&old.ssh, &new.ssh));
+    diffs.extend(compare_shell(&old.shell, &new.shell));
 
     diffs
 }
@@ -0,0 +1,138 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+
+/// Records the progress of a setup run, so an interrupted run can be resumed
+/// from the step that failed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    /// The configuration the run was started with, as given on the command line.
+    pub config: String,
+
+    /// Fingerprint of the configuration the run applies, see
+    /// [`crate::config::Configuration::fingerprint`].
+    #[serde(default)]
+    pub fingerprint: String,
+
+    pub started_at: String,
+
+    #[serde(default)]
+    pub completed: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed: Option<String>,
+}
+
+impl Journal {
+    pub fn new(config: &str) -> Self {
+        Self {
+            config: config.to_string(),
+            fingerprint: String::new(),
+            started_at: chrono::Local::now().to_rfc3339(),
+            completed: Vec::new(),
+            failed: None,
+        }
+    }
+
+    pub fn path() -> Result<PathBuf, Error> {
+        let home_dir = dirs::home_dir().context("Failed to get home directory")?;
+        Ok(home_dir.join(".oh-my-droid/journal.toml"))
+    }
+
+    /// Loads the journal of the last interrupted run, if any.
+    pub fn load() -> Result<Option<Journal>, Error> {
+        Self::load_from(&Self::path()?)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Option<Journal>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let toml_str = fs::read_to_string(path).context("Failed to read journal")?;
+        let journal = toml::from_str(&toml_str).context("Failed to parse journal")?;
+        Ok(Some(journal))
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        self.save_to(&Self::path()?)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(
+            path,
+            toml::to_string(self).context("Failed to serialize journal")?,
+        )
+        .context("Failed to write journal")
+    }
+
+    /// Removes the journal once a run has completed.
+    pub fn clear() -> Result<(), Error> {
+        let path = Self::path()?;
+        if path.exists() {
+            fs::remove_file(path).context("Failed to remove journal")?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a resumed run applies the configuration the interrupted
+    /// run was started with, otherwise the steps completed with the old one
+    /// would be skipped and recorded as applied.
+    pub fn check_fingerprint(&self, fingerprint: &str) -> Result<(), Error> {
+        match self.fingerprint == fingerprint {
+            true => Ok(()),
+            false => Err(Error::msg(
+                "The configuration changed since the interrupted run. Run `oh-my-droid setup` without --resume to apply it",
+            )),
+        }
+    }
+
+    pub fn is_completed(&self, step: &str) -> bool {
+        self.completed.iter().any(|s| s == step)
+    }
+
+    pub fn complete(&mut self, step: &str) {
+        if !self.is_completed(step) {
+            self.completed.push(step.to_string());
+        }
+        if self.failed.as_deref() == Some(step) {
+            self.failed = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let path =
+            std::env::temp_dir().join(format!("oh-my-droid-journal-{}.toml", std::process::id()));
+        assert_eq!(Journal::load_from(&path).unwrap(), None);
+
+        let mut journal = Journal::new("github:tsirysndr/pkgs");
+        journal.fingerprint = "cbf29ce484222325".into();
+        journal.complete("paths");
+        journal.complete("apt-get");
+        journal.complete("apt-get");
+        journal.failed = Some("curl".into());
+        journal.save_to(&path).unwrap();
+
+        let loaded = Journal::load_from(&path).unwrap().unwrap();
+        assert_eq!(loaded, journal);
+        assert_eq!(loaded.completed, vec!["paths", "apt-get"]);
+        assert!(loaded.is_completed("apt-get"));
+        assert!(!loaded.is_completed("curl"));
+        assert!(loaded.check_fingerprint("cbf29ce484222325").is_ok());
+        assert!(loaded.check_fingerprint("af63bd4c8601b7df").is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
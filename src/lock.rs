@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{apply::nix_store_paths, command::CommandRunner, config::Configuration};
+
+/// The lock file: the configuration that was applied, along with the state
+/// resolved while applying it, which is not part of the configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lock {
+    #[serde(flatten)]
+    pub config: Configuration,
+
+    /// The store paths the `[nix]` packages resolved to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "nix-store-paths")]
+    pub nix_store_paths: Option<HashMap<String, Vec<String>>>,
+
+    /// The apt packages oh-my-droid put on hold, the only ones it releases
+    /// when their `hold` is dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "apt-held")]
+    pub apt_held: Option<Vec<String>>,
+}
+
+impl Lock {
+    pub fn empty() -> Self {
+        Self {
+            config: Configuration::empty(),
+            nix_store_paths: None,
+            apt_held: None,
+        }
+    }
+
+    pub fn path() -> Result<PathBuf, Error> {
+        let home_dir = dirs::home_dir().context("Failed to get home directory")?;
+        Ok(home_dir.join(".oh-my-droid/lock.toml"))
+    }
+
+    pub fn exists() -> Result<bool, Error> {
+        Ok(Self::path()?.exists())
+    }
+
+    /// Loads the lock file, or an empty lock if nothing was applied yet.
+    pub fn load() -> Result<Lock, Error> {
+        let path = Self::path()?;
+        match path.exists() {
+            true => Self::load_from(&path),
+            false => Ok(Self::empty()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Lock, Error> {
+        let toml_str = fs::read_to_string(path).context("Failed to read lock file")?;
+        toml::from_str(&toml_str).context("Failed to parse lock file")
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        self.save_to(&Self::path()?)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // the apt-get step reads the lock while later steps are recorded
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(
+            &tmp,
+            toml::to_string(self).context("Failed to serialize config")?,
+        )
+        .context("Failed to write lock file")?;
+        fs::rename(&tmp, path).context("Failed to write lock file")
+    }
+
+    /// Records the section applied by the setup step `step` from `cfg`,
+    /// resolving the nix store paths once the `[nix]` packages are installed.
+    pub fn apply_section(
+        &mut self,
+        cfg: &Configuration,
+        step: &str,
+        runner: &dyn CommandRunner,
+    ) -> Result<(), Error> {
+        self.config.apply_section(cfg, step);
+        if step == "apt-get" {
+            let held = self
+                .config
+                .apt_get
+                .iter()
+                .flatten()
+                .filter(|package| package.hold())
+                .map(|package| package.name().to_string())
+                .collect::<Vec<_>>();
+            self.apt_held = (!held.is_empty()).then_some(held);
+        }
+        if step == "nix" {
+            self.nix_store_paths = match &self.config.nix {
+                Some(nix) => Some(nix_store_paths(runner, nix)?),
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// Records `cfg` as applied once every step succeeded, keeping the
+    /// state resolved along the way.
+    pub fn complete(&mut self, cfg: &Configuration) {
+        self.config = cfg.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::RecordingRunner;
+
+    const NIX_PROFILE: &str = "Name:               ripgrep
+Flake attribute:    legacyPackages.x86_64-linux.ripgrep
+Original flake URL: flake:nixpkgs
+Locked flake URL:   github:NixOS/nixpkgs/5633bcff0c6162b9e4b5f1264264611e950c8ec7
+Store paths:        /nix/store/2x0ay0zy2cvq6fkpkw2ckz1d6fd1xdl1-ripgrep-14.1.1
+";
+
+    #[test]
+    fn test_lock_keeps_nix_store_paths() {
+        let path =
+            std::env::temp_dir().join(format!("oh-my-droid-lock-{}.toml", std::process::id()));
+        let cfg: Configuration = toml::from_str(
+            r#"
+            path = ["~/go/bin"]
+
+            [nix]
+            ripgrep = "latest"
+            "#,
+        )
+        .unwrap();
+        assert!(!toml::to_string(&cfg).unwrap().contains("nix-store-paths"));
+
+        let runner = RecordingRunner::new().with_output("nix profile list", NIX_PROFILE);
+        let mut lock = Lock::empty();
+        for step in ["nix", "shell-init"] {
+            lock.apply_section(&cfg, step, &runner).unwrap();
+        }
+        lock.complete(&cfg);
+        lock.save_to(&path).unwrap();
+
+        let loaded = Lock::load_from(&path).unwrap();
+        assert_eq!(loaded.config.nix, cfg.nix);
+        assert_eq!(loaded.config.path, cfg.path);
+        assert_eq!(
+            loaded.nix_store_paths,
+            Some(HashMap::from([(
+                "ripgrep".to_string(),
+                vec!["/nix/store/2x0ay0zy2cvq6fkpkw2ckz1d6fd1xdl1-ripgrep-14.1.1".to_string()]
+            )]))
+        );
+
+        // removing the [nix] section drops its store paths
+        let mut lock = loaded;
+        lock.apply_section(&Configuration::empty(), "nix", &runner)
+            .unwrap();
+        assert_eq!(lock.nix_store_paths, None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lock_records_held_apt_packages() {
+        let runner = RecordingRunner::new();
+        let cfg: Configuration =
+            toml::from_str(r#"apt-get = ["git", { name = "tmux", hold = true }]"#).unwrap();
+        let mut lock = Lock::empty();
+        lock.apply_section(&cfg, "apt-get", &runner).unwrap();
+        assert_eq!(lock.apt_held, Some(vec!["tmux".to_string()]));
+
+        let lock: Lock = toml::from_str(&toml::to_string(&lock).unwrap()).unwrap();
+        assert_eq!(lock.apt_held, Some(vec!["tmux".to_string()]));
+
+        let mut lock = lock;
+        let cfg: Configuration = toml::from_str(r#"apt-get = ["git", "tmux"]"#).unwrap();
+        lock.apply_section(&cfg, "apt-get", &runner).unwrap();
+        assert_eq!(lock.apt_held, None);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Context, Error};
+
+use crate::command::Invocation;
+
+/// A timestamped log file recording every command started during one run.
+#[derive(Debug)]
+pub struct RunLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl RunLog {
+    /// Creates a new log file under `~/.oh-my-droid/logs`.
+    pub fn create() -> Result<Self, Error> {
+        Self::create_in(&logs_dir()?)
+    }
+
+    pub fn create_in(dir: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(dir).context("Failed to create logs directory")?;
+        let now = chrono::Local::now();
+        let path = dir.join(format!("{}.log", now.format("%Y-%m-%dT%H-%M-%S%.3f")));
+
+        let mut file = File::create(&path).context("Failed to create log file")?;
+        writeln!(
+            file,
+            "# oh-my-droid {} run started at {}",
+            env!("CARGO_PKG_VERSION"),
+            now.to_rfc3339()
+        )?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn command_started(&self, invocation: &Invocation) {
+        let prefix = label_prefix(invocation);
+        let mut entry = format!("\n{}$ {}\n", prefix, invocation.command_line());
+        for (key, value) in &invocation.env {
+            entry.push_str(&format!("{}  env {}={}\n", prefix, key, value));
+        }
+        self.write(entry.as_bytes());
+    }
+
+    pub fn output(&self, bytes: &[u8]) {
+        self.write(bytes);
+    }
+
+    pub fn command_finished(&self, invocation: &Invocation, status: &str, duration: Duration) {
+        let entry = format!(
+            "{}[{} after {:.2}s]\n",
+            label_prefix(invocation),
+            status,
+            duration.as_secs_f64()
+        );
+        self.write(entry.as_bytes());
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.write_all(bytes);
+        }
+    }
+}
+
+fn label_prefix(invocation: &Invocation) -> String {
+    invocation
+        .label
+        .as_ref()
+        .map(|label| format!("[{}] ", label))
+        .unwrap_or_default()
+}
+
+pub fn logs_dir() -> Result<PathBuf, Error> {
+    let home_dir = dirs::home_dir().context("Failed to get home directory")?;
+    Ok(home_dir.join(".oh-my-droid").join("logs"))
+}
+
+/// Lists the log files in `dir`, oldest first.
+pub fn list_logs(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut logs = fs::read_dir(dir)
+        .context("Failed to read logs directory")?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect::<Vec<_>>();
+    logs.sort();
+    Ok(logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_log_records_commands() {
+        let dir = std::env::temp_dir().join(format!("oh-my-droid-logs-{}", std::process::id()));
+        let log = RunLog::create_in(&dir).unwrap();
+        let invocation = Invocation::new("git", &["pull"]).env("PATH", "/usr/bin");
+        log.command_started(&invocation);
+        log.output(b"Already up to date.\n");
+        log.command_finished(&invocation, "exit code 0", Duration::from_millis(1500));
+
+        let content = fs::read_to_string(log.path()).unwrap();
+        assert!(content.starts_with("# oh-my-droid "));
+        assert!(content.contains("\n$ git pull\n  env PATH=/usr/bin\nAlready up to date.\n"));
+        assert!(content.ends_with("[exit code 0 after 1.50s]\n"));
+
+        std::thread::sleep(Duration::from_millis(2));
+        let second = RunLog::create_in(&dir).unwrap();
+        assert_ne!(log.path(), second.path());
+        let logs = list_logs(&dir).unwrap();
+        assert_eq!(logs, vec![log.path(), second.path()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
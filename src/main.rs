@@ -1,9 +1,16 @@
 use anyhow::Error;
-use clap::{Command, arg};
+use clap::{ArgMatches, Command, arg, value_parser};
 use owo_colors::OwoColorize;
 
 use crate::{
-    cmd::{init::init, setup::setup},
+    cmd::{
+        init::init,
+        logs::logs,
+        pin_installers::pin_installers,
+        setup::{SetupOptions, report_failure, setup},
+        shell_init::shell_init,
+    },
+    config::{ApplyOptions, RetryConfig},
     consts::CONFIG_FILE,
 };
 
@@ -14,6 +21,12 @@ pub mod config;
 pub mod consts;
 pub mod diff;
 pub mod git;
+pub mod journal;
+pub mod lock;
+pub mod logs;
+pub mod scheduler;
+pub mod shell;
+pub mod teardown;
 
 fn cli() -> Command {
     let banner = format!(
@@ -35,7 +48,7 @@ fn cli() -> Command {
         .version(env!("CARGO_PKG_VERSION"))
         .author("Tsiry Sandratraina <tsiry.sndr@rocksky.app>")
         .about(&banner)
-        .subcommand(Command::new("init").about(&format!(
+        .subcommand(Command::new("init").about(format!(
             "Write the initial configuration file {}.",
             CONFIG_FILE.green()
         )))
@@ -44,14 +57,60 @@ fn cli() -> Command {
                 .about("Set up the environment with the default configuration.")
                 .arg(arg!(-d --"dry-run" "Simulate the setup process without making any changes."))
                 .arg(arg!(-y --"yes" "Skip confirmation prompts during setup."))
+                .arg(arg!(--resume "Resume the last interrupted setup from the step that failed."))
+                .arg(
+                    arg!(-j --jobs <N> "Number of setup steps to run in parallel.")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1"),
+                )
+                .arg(
+                    arg!(--timeout <SECS> "Timeout in seconds for network-bound commands.")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--retries <N> "Number of retries for failed network-bound commands.")
+                        .value_parser(value_parser!(u32)),
+                )
                 .arg(
                     arg!([config] "Path to a custom configuration file or a remote git repository e.g., github:tsirysndr/pkgs")
                         .default_value(CONFIG_FILE),
                 )
                 .alias("apply"),
         )
+        .subcommand(
+            Command::new("shell-init")
+                .about("Print the shell init script, e.g. eval \"$(oh-my-droid shell-init bash)\".")
+                .arg(
+                    arg!([shell] "Shell to print the init script for, defaults to the configured shell.")
+                        .value_parser(["bash", "zsh", "fish"]),
+                ),
+        )
+        .subcommand(
+            Command::new("logs")
+                .about("List previous setup runs or show the log of one of them.")
+                .arg(arg!([run] "Name of the run to show, or 'last' for the most recent one.")),
+        )
+        .subcommand(
+            Command::new("pin-installers")
+                .about("Record the sha256 of the [curl] installer scripts in the configuration file.")
+                .arg(arg!([config] "Path to the configuration file.").default_value(CONFIG_FILE)),
+        )
         .arg(arg!(-d --"dry-run" "Simulate the setup process without making any changes."))
         .arg(arg!(-y --"yes" "Skip confirmation prompts during setup."))
+        .arg(arg!(--resume "Resume the last interrupted setup from the step that failed."))
+        .arg(
+            arg!(-j --jobs <N> "Number of setup steps to run in parallel.")
+                .value_parser(value_parser!(usize))
+                .default_value("1"),
+        )
+        .arg(
+            arg!(--timeout <SECS> "Timeout in seconds for network-bound commands.")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--retries <N> "Number of retries for failed network-bound commands.")
+                .value_parser(value_parser!(u32)),
+        )
         .arg(
             arg!([config] "Path to a custom configuration file or a remote git repository e.g., github:tsirysndr/pkgs")
                 .default_value(CONFIG_FILE),
@@ -59,24 +118,52 @@ fn cli() -> Command {
         .after_help("If no subcommand is provided, the 'setup' command will be executed by default.")
 }
 
+fn setup_options(args: &ArgMatches) -> SetupOptions {
+    SetupOptions {
+        no_confirm: args.get_flag("yes"),
+        resume: args.get_flag("resume"),
+        config_path: args.get_one::<String>("config").unwrap().clone(),
+        apply: ApplyOptions {
+            dry_run: args.get_flag("dry-run"),
+            jobs: *args.get_one::<usize>("jobs").unwrap(),
+            retry: RetryConfig {
+                timeout: args.get_one::<u64>("timeout").copied(),
+                retries: args.get_one::<u32>("retries").copied(),
+                backoff: None,
+            },
+        },
+    }
+}
+
 fn main() -> Result<(), Error> {
     let matches = cli().get_matches();
 
     match matches.subcommand() {
         Some(("init", _)) => init()?,
-        Some(("setup", args)) => {
-            let yes = args.get_flag("yes");
-            let dry_run = args.get_flag("dry-run");
-            let config = args.get_one::<String>("config").unwrap();
-            setup(dry_run, yes, config)?
+        Some(("logs", args)) => {
+            let run = args.get_one::<String>("run");
+            logs(run.map(String::as_str))?
+        }
+        Some(("shell-init", args)) => {
+            let shell = args.get_one::<String>("shell");
+            shell_init(shell.map(String::as_str))?
         }
-        _ => {
-            let yes = matches.get_flag("yes");
-            let dry_run = matches.get_flag("dry-run");
-            let config = matches.get_one::<String>("config").unwrap();
-            setup(dry_run, yes, config)?
+        Some(("pin-installers", args)) => {
+            pin_installers(args.get_one::<String>("config").unwrap())?
         }
+        Some(("setup", args)) => setup(&setup_options(args)).or_else(report_failure)?,
+        _ => setup(&setup_options(&matches)).or_else(report_failure)?,
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        cli().debug_assert();
+    }
+}
@@ -0,0 +1,329 @@
+use std::{sync::mpsc, thread};
+
+use anyhow::Error;
+
+use crate::apply::{Resource, SetupStep};
+
+/// What the scheduler needs to know about a step, see [`SetupStep`].
+pub trait Schedulable: Sync {
+    fn name(&self) -> &str;
+    fn dependencies(&self) -> &[&str];
+    fn resources(&self) -> &[Resource];
+}
+
+impl Schedulable for SetupStep<'_> {
+    fn name(&self) -> &str {
+        SetupStep::name(self)
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        SetupStep::dependencies(self)
+    }
+
+    fn resources(&self) -> &[Resource] {
+        SetupStep::resources(self)
+    }
+}
+
+/// Runs `steps` on up to `jobs` threads. A step starts once the steps it
+/// depends on have completed and no running step holds one of its
+/// [`SetupStep::resources`]; `steps` is expected in topological order, see
+/// [`crate::apply::sort_steps`].
+///
+/// `run` is called on worker threads, `on_done` on the calling thread with
+/// each step's result. Once `run` or `on_done` fails no new step is started,
+/// and the first error is returned after the running steps have finished.
+/// Steps whose dependencies can never complete are reported as an error too.
+pub fn run_steps<S, R, D>(steps: &[S], jobs: usize, run: R, mut on_done: D) -> Result<(), Error>
+where
+    S: Schedulable,
+    R: Fn(&S) -> Result<(), Error> + Sync,
+    D: FnMut(&S, Result<(), Error>) -> Result<(), Error>,
+{
+    let jobs = jobs.max(1);
+    let mut started = vec![false; steps.len()];
+    let mut done = vec![false; steps.len()];
+    let mut running = Vec::new();
+    let mut first_error = None;
+
+    let is_ready = |index: usize, done: &[bool], running: &[usize]| {
+        let step = &steps[index];
+        let dependencies_done = step.dependencies().iter().all(|dep| {
+            steps
+                .iter()
+                .enumerate()
+                .all(|(i, s)| s.name() != *dep || done[i])
+        });
+        let resources_free = running.iter().all(|&i| {
+            steps[i]
+                .resources()
+                .iter()
+                .all(|resource| !step.resources().contains(resource))
+        });
+        dependencies_done && resources_free
+    };
+
+    thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+        let run = &run;
+
+        loop {
+            while first_error.is_none() && running.len() < jobs {
+                let Some(index) =
+                    (0..steps.len()).find(|&i| !started[i] && is_ready(i, &done, &running))
+                else {
+                    break;
+                };
+                started[index] = true;
+                running.push(index);
+                let tx = tx.clone();
+                let step = &steps[index];
+                s.spawn(move || {
+                    let _ = tx.send((index, run(step)));
+                });
+            }
+
+            if running.is_empty() {
+                break;
+            }
+
+            let (index, result) = rx.recv().expect("a worker is running");
+            running.retain(|&i| i != index);
+            match on_done(&steps[index], result) {
+                Ok(()) => done[index] = true,
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+    });
+
+    if let Some(err) = first_error {
+        return Err(err);
+    }
+    let never_started = steps
+        .iter()
+        .zip(&started)
+        .filter(|(_, started)| !**started)
+        .map(|(step, _)| step.name())
+        .collect::<Vec<_>>();
+    match never_started.is_empty() {
+        true => Ok(()),
+        false => Err(Error::msg(format!(
+            "Steps never started, their dependencies cannot complete: {}",
+            never_started.join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Condvar, Mutex},
+        time::Duration,
+    };
+
+    use crate::config::{Configuration, SshConfig};
+
+    use super::*;
+
+    /// The order in which steps started and finished.
+    #[derive(Default)]
+    struct Timeline {
+        events: Mutex<Vec<(&'static str, bool)>>,
+    }
+
+    impl Timeline {
+        fn record(
+            &self,
+            step: &SetupStep,
+            run: impl FnOnce() -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            self.events.lock().unwrap().push((step.name(), true));
+            let result = run();
+            self.events.lock().unwrap().push((step.name(), false));
+            result
+        }
+
+        fn position(&self, name: &str, started: bool) -> usize {
+            let events = self.events.lock().unwrap();
+            events
+                .iter()
+                .position(|event| *event == (name, started))
+                .unwrap()
+        }
+
+        fn overlap(&self, a: &str, b: &str) -> bool {
+            self.position(a, true) < self.position(b, false)
+                && self.position(b, true) < self.position(a, false)
+        }
+    }
+
+    /// Lets steps through once `parties` of them are waiting, which they
+    /// only can when running at the same time.
+    struct Rendezvous {
+        parties: usize,
+        arrived: Mutex<usize>,
+        all_arrived: Condvar,
+    }
+
+    impl Rendezvous {
+        fn new(parties: usize) -> Self {
+            Self {
+                parties,
+                arrived: Mutex::new(0),
+                all_arrived: Condvar::new(),
+            }
+        }
+
+        fn wait(&self) -> Result<(), Error> {
+            let mut arrived = self.arrived.lock().unwrap();
+            *arrived += 1;
+            self.all_arrived.notify_all();
+            let (arrived, _) = self
+                .all_arrived
+                .wait_timeout_while(arrived, Duration::from_secs(10), |arrived| {
+                    *arrived < self.parties
+                })
+                .unwrap();
+            match *arrived < self.parties {
+                true => Err(Error::msg("Timed out waiting for the other steps")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_steps_in_parallel() {
+        let map = HashMap::from([("node".to_string(), "latest".to_string())]);
+        let installers = HashMap::from([("bun".to_string(), "https://bun.sh/install".into())]);
+        let cfg = Configuration {
+            apt_get: Some(vec!["git".into()]),
+            ..Configuration::empty()
+        };
+        let steps = vec![
+            SetupStep::AptGet(&cfg),
+            SetupStep::Curl(&installers),
+            SetupStep::Stow(&map),
+            SetupStep::Mise(&map),
+            SetupStep::Tailscale(true),
+            SetupStep::Pkgx(&map),
+        ];
+        let timeline = Timeline::default();
+        // curl and stow only finish once both are running
+        let rendezvous = Rendezvous::new(2);
+        let mut completed = Vec::new();
+        run_steps(
+            &steps,
+            4,
+            |step| {
+                timeline.record(step, || match step.name() {
+                    "curl" | "stow" => rendezvous.wait(),
+                    _ => Ok(()),
+                })
+            },
+            |step, result| {
+                completed.push(step.name());
+                result
+            },
+        )
+        .unwrap();
+
+        assert_eq!(completed.len(), steps.len());
+        assert_eq!(completed[0], "apt-get");
+        assert!(timeline.overlap("curl", "stow"));
+        assert!(timeline.position("curl", false) < timeline.position("pkgx", true));
+        // mise and the tailscale installer both need the dpkg lock
+        assert!(!timeline.overlap("mise", "tailscale"));
+    }
+
+    #[test]
+    fn test_run_steps_stops_after_failure() {
+        let (installers, map) = (HashMap::new(), HashMap::new());
+        let steps = vec![SetupStep::Curl(&installers), SetupStep::Pkgx(&map)];
+        let ran = Mutex::new(Vec::new());
+        let err = run_steps(
+            &steps,
+            2,
+            |step| {
+                ran.lock().unwrap().push(step.name());
+                Err(Error::msg(format!("{} failed", step.name())))
+            },
+            |_, result| result,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.to_string(), "curl failed");
+        assert_eq!(*ran.lock().unwrap(), vec!["curl"]);
+    }
+
+    struct Step(&'static str, &'static [&'static str], &'static [Resource]);
+
+    impl Schedulable for Step {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn dependencies(&self) -> &[&str] {
+            self.1
+        }
+
+        fn resources(&self) -> &[Resource] {
+            self.2
+        }
+    }
+
+    #[test]
+    fn test_run_steps_reports_steps_that_never_start() {
+        let steps = [
+            Step("a", &["b"], &[]),
+            Step("b", &["a"], &[]),
+            Step("c", &[], &[]),
+            Step("d", &["a"], &[]),
+        ];
+        let ran = Mutex::new(Vec::new());
+        let err = run_steps(
+            &steps,
+            2,
+            |step| {
+                ran.lock().unwrap().push(step.0);
+                Ok(())
+            },
+            |_, result| result,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Steps never started, their dependencies cannot complete: a, b, d"
+        );
+        assert_eq!(*ran.lock().unwrap(), vec!["c"]);
+    }
+
+    #[test]
+    fn test_interactive_steps_run_one_at_a_time() {
+        let ssh = SshConfig {
+            port: None,
+            authorized_keys: None,
+        };
+        let steps = vec![
+            SetupStep::Ssh(&ssh),
+            SetupStep::Tailscale(true),
+            SetupStep::Doppler(true),
+        ];
+        assert!(steps.iter().all(SetupStep::is_interactive));
+        let timeline = Timeline::default();
+        run_steps(
+            &steps,
+            4,
+            |step| timeline.record(step, || Ok(())),
+            |_, result| result,
+        )
+        .unwrap();
+        assert!(!timeline.overlap("ssh", "tailscale"));
+        assert!(!timeline.overlap("ssh", "doppler"));
+        assert!(!timeline.overlap("tailscale", "doppler"));
+        assert!(!SetupStep::Paths.is_interactive());
+    }
+}
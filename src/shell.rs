@@ -0,0 +1,684 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Configuration, PromptConfig, PromptProvider};
+
+/// Marks the line of the rc file that sources the init script.
+const RC_MARKER: &str = "# oh-my-droid";
+
+/// Lines previous versions appended to `~/.bashrc` directly, now part of
+/// the init script.
+const LEGACY_RC_LINES: &[&str] = &[
+    "export PATH=\"$HOME/.local/bin:$PATH\"",
+    "export PATH=\"/nix/var/nix/profiles/default/bin:$PATH\"",
+    "eval \"$(mise activate bash)\"",
+    "eval \"$(zoxide init bash)\"",
+    "source ble.sh/out/ble.sh",
+    "neofetch",
+];
+
+const LEGACY_OH_MY_POSH_PREFIX: &str =
+    "eval \"$(oh-my-posh init bash --config $HOME/.cache/oh-my-posh/themes/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub const ALL: [Shell; 3] = [Shell::Bash, Shell::Zsh, Shell::Fish];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+        }
+    }
+
+    /// The login shell from `$SHELL`, bash when it is unset or unsupported.
+    pub fn detect() -> Shell {
+        std::env::var("SHELL")
+            .ok()
+            .and_then(|path| Shell::from_path(&path))
+            .unwrap_or(Shell::Bash)
+    }
+
+    pub fn from_path(path: &str) -> Option<Shell> {
+        Shell::from_name(Path::new(path).file_name()?.to_str()?)
+    }
+
+    pub fn from_name(name: &str) -> Option<Shell> {
+        Shell::ALL.into_iter().find(|shell| shell.name() == name)
+    }
+
+    /// The rc file sourcing the init script, relative to the home directory.
+    pub fn rc_file(&self) -> &'static str {
+        match self {
+            Shell::Bash => ".bashrc",
+            Shell::Zsh => ".zshrc",
+            Shell::Fish => ".config/fish/config.fish",
+        }
+    }
+
+    /// The init script generated from the configuration, relative to the
+    /// home directory.
+    pub fn init_script(&self) -> &'static str {
+        match self {
+            Shell::Bash => ".oh-my-droid/init.bash",
+            Shell::Zsh => ".oh-my-droid/init.zsh",
+            Shell::Fish => ".oh-my-droid/init.fish",
+        }
+    }
+
+    /// The line of the rc file that sources the init script.
+    pub fn rc_line(&self) -> String {
+        let script = self.init_script();
+        match self {
+            Shell::Bash | Shell::Zsh => {
+                format!("[ -f ~/{script} ] && source ~/{script} {RC_MARKER}")
+            }
+            Shell::Fish => format!("test -f ~/{script}; and source ~/{script} {RC_MARKER}"),
+        }
+    }
+
+    fn prepend_path(&self, dir: &str) -> String {
+        let dir = escape_double_quoted(dir);
+        match self {
+            Shell::Bash | Shell::Zsh => format!("export PATH=\"{dir}:$PATH\""),
+            Shell::Fish => format!("set -gx PATH \"{dir}\" $PATH"),
+        }
+    }
+
+    fn alias(&self, name: &str, command: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => format!("alias {name}={}", single_quoted(command)),
+            Shell::Fish => format!("alias {name} {}", self.single_quoted(command)),
+        }
+    }
+
+    fn single_quoted(&self, value: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => single_quoted(value),
+            Shell::Fish => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+        }
+    }
+
+    /// Defines the function `name`. The body is written as is, in the
+    /// shell's own syntax.
+    fn function(&self, name: &str, body: &str) -> Vec<String> {
+        let (open, close) = match self {
+            Shell::Bash | Shell::Zsh => (format!("{name}() {{"), "}"),
+            Shell::Fish => (format!("function {name}"), "end"),
+        };
+        let mut lines = vec![open];
+        lines.extend(body.trim_end().lines().map(String::from));
+        lines.push(close.to_string());
+        lines
+    }
+
+    /// Exports `name`, with `value` double quoted so that it can still
+    /// refer to other variables.
+    fn export(&self, name: &str, value: &str) -> String {
+        let value = escape_double_quoted(value);
+        match self {
+            Shell::Bash | Shell::Zsh => format!("export {name}=\"{value}\""),
+            Shell::Fish => format!("set -gx {name} \"{value}\""),
+        }
+    }
+
+    /// Runs `command` when `program` is installed.
+    fn when_installed(&self, program: &str, command: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => {
+                format!("command -v {program} >/dev/null 2>&1 && {command}")
+            }
+            Shell::Fish => format!("command -q {program}; and {command}"),
+        }
+    }
+
+    /// Evaluates the output of `init`, a tool's shell integration command.
+    fn hook(&self, program: &str, init: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => {
+                self.when_installed(program, &format!("eval \"$({init})\""))
+            }
+            Shell::Fish => self.when_installed(program, &format!("{init} | source")),
+        }
+    }
+
+    /// Wraps `lines` in a block that only runs in interactive shells. The
+    /// script may be `eval`ed from an rc file, so it cannot `return`. The
+    /// lines are not indented, which would break heredocs in functions.
+    fn interactive(&self, lines: Vec<String>) -> Vec<String> {
+        if lines.is_empty() {
+            return lines;
+        }
+        let (open, close) = match self {
+            Shell::Bash => ("if [[ $- == *i* ]]; then", "fi"),
+            Shell::Zsh => ("if [[ -o interactive ]]; then", "fi"),
+            Shell::Fish => ("if status is-interactive", "end"),
+        };
+        let mut block = vec![open.to_string()];
+        block.extend(lines);
+        block.push(close.to_string());
+        block
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Renders the init script of `shell` for `cfg`.
+pub fn render_init_script(cfg: &Configuration, shell: Shell) -> String {
+    let mut script = vec![
+        "# Generated by oh-my-droid from your configuration, do not edit.".to_string(),
+        "# It is rewritten every time the configuration is applied.".to_string(),
+        String::new(),
+        shell.prepend_path("$HOME/.local/bin"),
+        shell.prepend_path("/nix/var/nix/profiles/default/bin"),
+    ];
+
+    // prepended last to first, so the first entry ends up first in PATH
+    for dir in cfg.path.iter().flatten().rev() {
+        script.push(shell.prepend_path(dir));
+    }
+
+    for (name, value) in cfg.env_vars() {
+        script.push(shell.export(&name, &value));
+    }
+
+    if cfg.mise.is_some() {
+        script.push(shell.hook("mise", &format!("mise activate {shell}")));
+    }
+
+    // everything below only makes sense in an interactive shell
+    let mut interactive = Vec::new();
+
+    // ble.sh is a bash line editor
+    let blesh = cfg
+        .blesh
+        .as_ref()
+        .filter(|b| b.enabled() && shell == Shell::Bash);
+    if let Some(blesh) = blesh {
+        let script = escape_double_quoted(&blesh.script());
+        interactive.push(format!(
+            "[ -f \"{script}\" ] && source \"{script}\" --noattach"
+        ));
+    }
+
+    if let Some(aliases) = &cfg.alias {
+        let mut aliases = aliases.iter().collect::<Vec<_>>();
+        aliases.sort();
+        for (alias, command) in aliases {
+            interactive.push(shell.alias(alias, command));
+        }
+    }
+
+    if let Some(functions) = &cfg.functions {
+        let mut functions = functions.iter().collect::<Vec<_>>();
+        functions.sort();
+        for (name, body) in functions {
+            interactive.extend(shell.function(name, body));
+        }
+    }
+
+    if let Some(zoxide) = cfg.zoxide.as_ref().filter(|z| z.enabled()) {
+        let init = format!("zoxide init {shell} {}", zoxide.init_args());
+        interactive.push(shell.hook("zoxide", init.trim_end()));
+    }
+
+    if uses_atuin(cfg) {
+        interactive.push(shell.hook("atuin", &format!("atuin init {shell}")));
+    }
+
+    match cfg.prompt_provider() {
+        Some(PromptProvider::OhMyPosh) => {
+            let oh_my_posh = cfg.oh_my_posh.clone().unwrap_or_default();
+            let config = escape_double_quoted(&oh_my_posh.config_file());
+            interactive.push(shell.hook(
+                "oh-my-posh",
+                &format!("oh-my-posh init {shell} --config \"{config}\""),
+            ));
+        }
+        Some(PromptProvider::Starship) => {
+            if cfg
+                .prompt
+                .as_ref()
+                .is_some_and(|p| p.manages_starship_config())
+            {
+                let config = format!("~/{}", PromptConfig::STARSHIP_CONFIG);
+                interactive.push(shell.export("STARSHIP_CONFIG", &config));
+            }
+            interactive.push(shell.hook("starship", &format!("starship init {shell}")));
+        }
+        None => {}
+    }
+
+    if cfg.neofetch.unwrap_or(false) {
+        interactive.push(shell.when_installed("neofetch", "neofetch"));
+    }
+
+    if blesh.is_some() {
+        interactive.push("[[ ! ${BLE_VERSION-} ]] || ble-attach".to_string());
+    }
+
+    if !interactive.is_empty() {
+        script.push(String::new());
+        script.extend(shell.interactive(interactive));
+    }
+
+    script.push(String::new());
+    script.join("\n")
+}
+
+/// Single quotes `value` for bash and zsh, where nothing inside is
+/// expanded.
+fn single_quoted(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Escapes `value` for use between double quotes in bash, zsh and fish
+/// alike, leaving `$NAME` references to be expanded. A leading `~` becomes
+/// `$HOME`, as it would not expand inside quotes.
+fn escape_double_quoted(value: &str) -> String {
+    let value = match value.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("$HOME{rest}"),
+        _ => value.to_string(),
+    };
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Whether atuin is installed by one of the curl installers or pkgx.
+fn uses_atuin(cfg: &Configuration) -> bool {
+    let curl = cfg
+        .curl
+        .as_ref()
+        .is_some_and(|curl| curl.contains_key("atuin"));
+    let pkgx = cfg
+        .pkgx
+        .as_ref()
+        .is_some_and(|pkgx| pkgx.contains_key("atuin") || pkgx.contains_key("atuin.sh"));
+    curl || pkgx
+}
+
+/// Returns `rc` with the lines previously written by oh-my-droid removed
+/// and `rc_line`, the marked line sourcing the init script, at the end.
+/// `aliases` are the aliases the configuration defines, which older
+/// versions wrote to the rc file.
+pub fn update_rc(rc: &str, rc_line: &str, aliases: &[(&String, &String)]) -> String {
+    let legacy_aliases = aliases
+        .iter()
+        .map(|(alias, command)| format!("alias {}=\"{}\"", alias, command))
+        .collect::<Vec<_>>();
+
+    let mut lines = rc
+        .lines()
+        .filter(|line| {
+            let line = line.trim();
+            !line.ends_with(RC_MARKER)
+                && !LEGACY_RC_LINES.contains(&line)
+                && !line.starts_with(LEGACY_OH_MY_POSH_PREFIX)
+                && !legacy_aliases.iter().any(|alias| alias == line)
+        })
+        .collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push_str("\n\n");
+    }
+    updated.push_str(rc_line);
+    updated.push('\n');
+    updated
+}
+
+/// Writes the init script for `cfg` and, unless the configuration opts out
+/// with `manage_rc = false`, makes the rc file of its shell source it.
+pub fn install_init_script(cfg: &Configuration) -> Result<(), Error> {
+    let home = dirs::home_dir().context("Failed to get home directory")?;
+    install_init_script_in(cfg, &home)
+}
+
+pub fn install_init_script_in(cfg: &Configuration, home: &Path) -> Result<(), Error> {
+    let shell = cfg.shell();
+    write_atomically(
+        &home.join(shell.init_script()),
+        &render_init_script(cfg, shell),
+    )
+    .context("Failed to write the shell init script")?;
+
+    // scripts of a previously configured shell would go stale, the rc line
+    // sourcing them is a no-op once they are gone
+    for other in Shell::ALL.into_iter().filter(|other| *other != shell) {
+        let stale = home.join(other.init_script());
+        if stale.exists() {
+            fs::remove_file(&stale).context("Failed to remove a stale init script")?;
+        }
+    }
+
+    if !cfg.manage_rc() {
+        return Ok(());
+    }
+
+    // the rc file may be a symlink into a dotfiles repository
+    let rc_path = home.join(shell.rc_file());
+    let rc_path = fs::canonicalize(&rc_path).unwrap_or(rc_path);
+    let rc = match rc_path.exists() {
+        true => fs::read_to_string(&rc_path)
+            .with_context(|| format!("Failed to read {}", rc_path.display()))?,
+        false => String::new(),
+    };
+    let aliases = cfg
+        .alias
+        .as_ref()
+        .map(|aliases| aliases.iter().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let updated = update_rc(&rc, &shell.rc_line(), &aliases);
+    if updated != rc {
+        write_atomically(&rc_path, &updated)
+            .with_context(|| format!("Failed to update {}", rc_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it
+/// over `path`, so readers never see a partially written file.
+fn write_atomically(path: &Path, contents: &str) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file_name = path
+        .file_name()
+        .context("Invalid path")?
+        .to_string_lossy()
+        .to_string();
+    let tmp: PathBuf = path.with_file_name(format!(".{file_name}.tmp"));
+    fs::write(&tmp, contents)?;
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(&tmp, metadata.permissions())?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::{BleShConfig, OhMyPosh, ShellConfig, ZoxideConfig, ZoxideHook};
+
+    use super::*;
+
+    #[test]
+    fn test_render_init_script() {
+        let cfg = Configuration {
+            mise: Some(HashMap::new()),
+            alias: Some(HashMap::from([
+                ("lsd".to_string(), "eza".to_string()),
+                ("ls".to_string(), "eza -lh".to_string()),
+            ])),
+            zoxide: Some(ZoxideConfig::Enabled(true)),
+            neofetch: Some(false),
+            blesh: Some(BleShConfig::Enabled(false)),
+            oh_my_posh: Some(OhMyPosh {
+                theme: Some("atomic".into()),
+                ..Default::default()
+            }),
+            ..Configuration::empty()
+        };
+        let script = render_init_script(&cfg, Shell::Bash);
+        let lines = script.lines().collect::<Vec<_>>();
+
+        assert!(lines.contains(&"export PATH=\"$HOME/.local/bin:$PATH\""));
+        assert!(lines.contains(&"alias ls='eza -lh'"));
+        assert!(lines.contains(&"alias lsd='eza'"));
+        assert!(!script.contains("atuin"));
+        assert!(script.contains("mise activate bash"));
+        assert!(script.contains("zoxide init bash"));
+        assert!(script.contains("themes/atomic.omp.json"));
+        assert!(!script.contains("neofetch"));
+        assert!(!script.contains("ble.sh"));
+
+        let position = |needle: &str| lines.iter().position(|l| l.contains(needle)).unwrap();
+        assert!(position("mise activate") < position("$- == *i*"));
+        assert!(position("$- == *i*") < position("alias ls="));
+        assert_eq!(lines.last(), Some(&"fi"));
+    }
+
+    #[test]
+    fn test_render_starship_prompt() {
+        let cfg = Configuration {
+            prompt: Some(PromptConfig {
+                provider: PromptProvider::Starship,
+                preset: Some("tokyo-night".into()),
+                config: None,
+            }),
+            oh_my_posh: Some(OhMyPosh::default()),
+            ..Configuration::empty()
+        };
+        let script = render_init_script(&cfg, Shell::Bash);
+        assert!(!script.contains("oh-my-posh"));
+        assert!(script.contains(
+            "export STARSHIP_CONFIG=\"$HOME/.oh-my-droid/starship.toml\"\ncommand -v starship >/dev/null 2>&1 && eval \"$(starship init bash)\""
+        ));
+        let script = render_init_script(&cfg, Shell::Fish);
+        assert!(script.contains("command -q starship; and starship init fish | source"));
+
+        // without a preset or inline config, starship keeps its own config file
+        let cfg = Configuration {
+            prompt: Some(PromptConfig {
+                preset: None,
+                ..cfg.prompt.clone().unwrap()
+            }),
+            ..cfg
+        };
+        let script = render_init_script(&cfg, Shell::Zsh);
+        assert!(!script.contains("STARSHIP_CONFIG"));
+        assert!(script.contains("starship init zsh"));
+
+        let cfg = Configuration {
+            prompt: Some(PromptConfig {
+                provider: PromptProvider::OhMyPosh,
+                preset: None,
+                config: None,
+            }),
+            ..Configuration::empty()
+        };
+        let script = render_init_script(&cfg, Shell::Bash);
+        assert!(!script.contains("starship"));
+        assert!(script.contains("themes/tokyonight_storm.omp.json"));
+    }
+
+    #[test]
+    fn test_render_init_script_for_other_shells() {
+        let cfg = Configuration {
+            mise: Some(HashMap::new()),
+            curl: Some(HashMap::from([(
+                "atuin".to_string(),
+                "https://setup.atuin.sh".into(),
+            )])),
+            zoxide: Some(ZoxideConfig::Enabled(true)),
+            blesh: Some(BleShConfig::Enabled(true)),
+            ..Configuration::empty()
+        };
+
+        let bash = render_init_script(&cfg, Shell::Bash);
+        assert!(bash.contains(
+            "[ -f \"$HOME/.local/share/blesh/ble.sh\" ] && source \"$HOME/.local/share/blesh/ble.sh\" --noattach"
+        ));
+
+        let zoxide = Configuration {
+            zoxide: Some(ZoxideConfig::Options {
+                version: None,
+                cmd: Some("cd".into()),
+                hook: Some(ZoxideHook::Pwd),
+            }),
+            ..cfg.clone()
+        };
+        assert!(
+            render_init_script(&zoxide, Shell::Fish)
+                .contains("command -q zoxide; and zoxide init fish --cmd cd --hook pwd | source")
+        );
+
+        let zsh = render_init_script(&cfg, Shell::Zsh);
+        assert!(zsh.contains("eval \"$(mise activate zsh)\""));
+        assert!(zsh.contains("eval \"$(zoxide init zsh)\""));
+        assert!(zsh.contains("eval \"$(atuin init zsh)\""));
+        assert!(zsh.contains("if [[ -o interactive ]]; then"));
+        assert!(!zsh.contains("ble.sh"));
+
+        let fish = render_init_script(&cfg, Shell::Fish);
+        assert!(fish.contains("set -gx PATH \"$HOME/.local/bin\" $PATH"));
+        assert!(fish.contains("command -q mise; and mise activate fish | source"));
+        assert!(fish.contains("command -q zoxide; and zoxide init fish | source"));
+        assert!(fish.contains("command -q atuin; and atuin init fish | source"));
+        assert!(fish.contains("if status is-interactive"));
+        assert!(!fish.contains("eval"));
+    }
+
+    #[test]
+    fn test_render_env_and_path() {
+        let cfg = Configuration {
+            env: Some(HashMap::from([
+                ("GOPATH".to_string(), "~/go".to_string()),
+                ("EDITOR".to_string(), "nvim \"-u\" init.lua".to_string()),
+            ])),
+            path: Some(vec!["~/go/bin".to_string(), "/opt/bin".to_string()]),
+            ..Configuration::empty()
+        };
+
+        let bash = render_init_script(&cfg, Shell::Bash);
+        let lines = bash.lines().collect::<Vec<_>>();
+        let position = |line: &str| lines.iter().position(|l| *l == line).unwrap();
+        assert!(
+            position("export PATH=\"/opt/bin:$PATH\"")
+                < position("export PATH=\"$HOME/go/bin:$PATH\"")
+        );
+        assert!(lines.contains(&"export EDITOR=\"nvim \\\"-u\\\" init.lua\""));
+        assert!(lines.contains(&"export GOPATH=\"$HOME/go\""));
+
+        let fish = render_init_script(&cfg, Shell::Fish);
+        assert!(fish.contains("set -gx PATH \"$HOME/go/bin\" $PATH"));
+        assert!(fish.contains("set -gx GOPATH \"$HOME/go\""));
+    }
+
+    #[test]
+    fn test_render_quoted_aliases_and_functions() {
+        let cfg = Configuration {
+            alias: Some(HashMap::from([(
+                "hi".to_string(),
+                "echo \"it's $HOME\"".to_string(),
+            )])),
+            functions: Some(HashMap::from([(
+                "mkcd".to_string(),
+                "mkdir -p \"$1\"\ncd \"$1\"\n".to_string(),
+            )])),
+            ..Configuration::empty()
+        };
+
+        let bash = render_init_script(&cfg, Shell::Bash);
+        assert!(bash.contains("alias hi='echo \"it'\\''s $HOME\"'\n"));
+        assert!(bash.contains("mkcd() {\nmkdir -p \"$1\"\ncd \"$1\"\n}\n"));
+
+        let fish = render_init_script(&cfg, Shell::Fish);
+        assert!(fish.contains("alias hi 'echo \"it\\'s $HOME\"'\n"));
+        assert!(fish.contains("function mkcd\nmkdir -p \"$1\"\ncd \"$1\"\nend\n"));
+    }
+
+    #[test]
+    fn test_detect_shell_from_path() {
+        assert_eq!(Shell::from_path("/usr/bin/zsh"), Some(Shell::Zsh));
+        assert_eq!(Shell::from_path("/usr/local/bin/fish"), Some(Shell::Fish));
+        assert_eq!(Shell::from_path("/bin/bash"), Some(Shell::Bash));
+        assert_eq!(Shell::from_path("/bin/sh"), None);
+    }
+
+    #[test]
+    fn test_update_rc_replaces_legacy_lines() {
+        let ls = ("ls".to_string(), "eza -lh".to_string());
+        let rc = [
+            "# my settings",
+            "alias lsd=\"lsd -la\"",
+            "export PATH=\"$HOME/.local/bin:$PATH\"",
+            "eval \"$(mise activate bash)\"",
+            "alias ls=\"eza -lh\"",
+            "eval \"$(oh-my-posh init bash --config $HOME/.cache/oh-my-posh/themes/atomic.omp.json)\"",
+            "neofetch",
+            "",
+        ]
+        .join("\n");
+
+        let rc_line = Shell::Bash.rc_line();
+        let updated = update_rc(&rc, &rc_line, &[(&ls.0, &ls.1)]);
+        assert_eq!(
+            updated,
+            format!("# my settings\nalias lsd=\"lsd -la\"\n\n{}\n", rc_line)
+        );
+        assert_eq!(update_rc(&updated, &rc_line, &[]), updated);
+        assert_eq!(update_rc("", &rc_line, &[]), format!("{}\n", rc_line));
+    }
+
+    #[test]
+    fn test_install_init_script() {
+        let home = std::env::temp_dir().join(format!("oh-my-droid-home-{}", std::process::id()));
+        fs::create_dir_all(&home).unwrap();
+        fs::write(home.join(".bashrc"), "neofetch\n").unwrap();
+
+        let cfg = Configuration {
+            neofetch: Some(true),
+            shell: Some(ShellConfig::Name(Shell::Bash)),
+            ..Configuration::empty()
+        };
+        install_init_script_in(&cfg, &home).unwrap();
+
+        let script = fs::read_to_string(home.join(Shell::Bash.init_script())).unwrap();
+        assert!(script.contains("neofetch"));
+        let rc = fs::read_to_string(home.join(".bashrc")).unwrap();
+        assert_eq!(rc, format!("{}\n", Shell::Bash.rc_line()));
+
+        let cfg = Configuration {
+            shell: Some(ShellConfig::Name(Shell::Fish)),
+            ..cfg
+        };
+        install_init_script_in(&cfg, &home).unwrap();
+        assert!(!home.join(Shell::Bash.init_script()).exists());
+        assert!(home.join(Shell::Fish.init_script()).exists());
+        let rc = fs::read_to_string(home.join(".config/fish/config.fish")).unwrap();
+        assert_eq!(rc, format!("{}\n", Shell::Fish.rc_line()));
+
+        let cfg = Configuration {
+            shell: Some(ShellConfig::Options {
+                name: Some(Shell::Zsh),
+                chsh: false,
+                manage_rc: Some(false),
+            }),
+            ..cfg
+        };
+        install_init_script_in(&cfg, &home).unwrap();
+        assert!(home.join(Shell::Zsh.init_script()).exists());
+        assert!(!home.join(".zshrc").exists());
+
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{Context, Error};
+use owo_colors::OwoColorize;
+
+use crate::{
+    apply::{APT_DIR, NODE_ENV, expand_home},
+    command::CommandRunner,
+    config::BleShConfig,
+};
+
+/// Undoes configuration entries that were removed or disabled since the
+/// last run. Shell integrations (aliases, prompt, ...) need no teardown,
+/// they disappear from the regenerated init script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Teardown {
+    AptGet(Vec<String>),
+    /// Removes the sources file and keyring of the named apt repositories.
+    AptRepositories(Vec<String>),
+    Pkgx(Vec<String>),
+    Mise(Vec<String>),
+    Npm(Vec<String>),
+    /// Removes the named elements from the nix profile.
+    Nix(Vec<String>),
+    Stow,
+    /// Uninstalls ble.sh from the given prefix.
+    BleSh(String),
+    Zoxide,
+    Tailscale,
+    Doppler,
+}
+
+impl Teardown {
+    /// The teardown for `item` of the configuration section `parent`, if
+    /// removing it can be undone.
+    pub fn for_section(parent: &str, item: &str) -> Option<Teardown> {
+        let items = vec![item.to_string()];
+        match parent {
+            "apt-get" => Some(Teardown::AptGet(items)),
+            "apt" => Some(Teardown::AptRepositories(items)),
+            "pkgx" => Some(Teardown::Pkgx(items)),
+            "mise" => Some(Teardown::Mise(items)),
+            "npm" => Some(Teardown::Npm(items)),
+            "nix" => Some(Teardown::Nix(items)),
+            "stow" => Some(Teardown::Stow),
+            "ble.sh" => Some(Teardown::BleSh(item.to_string())),
+            "zoxide" => Some(Teardown::Zoxide),
+            "tailscale" => Some(Teardown::Tailscale),
+            "doppler" => Some(Teardown::Doppler),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Teardown::AptGet(_) => "remove:apt-get",
+            Teardown::AptRepositories(_) => "remove:apt",
+            Teardown::Pkgx(_) => "remove:pkgx",
+            Teardown::Mise(_) => "remove:mise",
+            Teardown::Npm(_) => "remove:npm",
+            Teardown::Nix(_) => "remove:nix",
+            Teardown::Stow => "remove:stow",
+            Teardown::BleSh(_) => "remove:ble.sh",
+            Teardown::Zoxide => "remove:zoxide",
+            Teardown::Tailscale => "remove:tailscale",
+            Teardown::Doppler => "remove:doppler",
+        }
+    }
+
+    /// The configuration section this teardown belongs to.
+    pub fn section(&self) -> &'static str {
+        self.name().trim_start_matches("remove:")
+    }
+
+    /// Adds the items of `other`, a teardown of the same section.
+    pub fn merge(&mut self, other: Teardown) {
+        match (self, other) {
+            (Teardown::AptGet(items), Teardown::AptGet(more))
+            | (Teardown::AptRepositories(items), Teardown::AptRepositories(more))
+            | (Teardown::Pkgx(items), Teardown::Pkgx(more))
+            | (Teardown::Mise(items), Teardown::Mise(more))
+            | (Teardown::Npm(items), Teardown::Npm(more))
+            | (Teardown::Nix(items), Teardown::Nix(more)) => {
+                for item in more {
+                    if !items.contains(&item) {
+                        items.push(item);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn run(&self, runner: &dyn CommandRunner) -> Result<(), Error> {
+        match self {
+            Teardown::AptGet(pkgs) => remove_apt(runner, pkgs),
+            Teardown::AptRepositories(names) => {
+                let mut args = vec!["rm".to_string(), "-f".to_string()];
+                for name in names {
+                    args.push(format!("{APT_DIR}/sources.list.d/{name}.sources"));
+                    args.push(format!("{APT_DIR}/keyrings/{name}.gpg"));
+                }
+                runner
+                    .run("sudo", &args.iter().map(String::as_str).collect::<Vec<_>>())
+                    .context("Failed to remove apt repositories")
+            }
+            Teardown::Pkgx(pkgs) => {
+                for name in pkgs {
+                    runner
+                        .run("pkgm", &["uninstall", name])
+                        .context(format!("Failed to uninstall {name} via pkgx"))?;
+                }
+                Ok(())
+            }
+            Teardown::Mise(tools) => {
+                for tool in tools {
+                    runner
+                        .run("mise", &["unuse", "-g", tool])
+                        .context(format!("Failed to remove {tool} via mise"))?;
+                }
+                Ok(())
+            }
+            Teardown::Npm(pkgs) => {
+                for package in pkgs {
+                    runner
+                        .run(
+                            "bash",
+                            &["-c", &format!("{NODE_ENV}; npm uninstall -g {}", package)],
+                        )
+                        .context(format!("Failed to uninstall npm package {package}"))?;
+                }
+                Ok(())
+            }
+            Teardown::Nix(names) => {
+                for name in names {
+                    runner
+                        .run("nix", &["profile", "remove", name])
+                        .context(format!("Failed to remove {name} from the nix profile"))?;
+                }
+                Ok(())
+            }
+            Teardown::Stow => runner
+                .run("bash", &["-c", "stow -D -d ~/.dotfiles -t ~ -- ."])
+                .context("Failed to unstow dotfiles"),
+            Teardown::BleSh(prefix) => {
+                let home =
+                    dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
+                let prefix = expand_home(&home, prefix);
+                let paths = [
+                    prefix.join("share/blesh"),
+                    prefix.join("share/doc/blesh"),
+                    prefix.join("share/licenses/blesh"),
+                    home.join(BleShConfig::SOURCE_DIR),
+                    // installed in place by earlier versions
+                    home.join("ble.sh"),
+                ];
+                let mut args = vec!["-rf".to_string()];
+                args.extend(paths.iter().map(|path| path.to_string_lossy().to_string()));
+                runner
+                    .run("rm", &args.iter().map(String::as_str).collect::<Vec<_>>())
+                    .context("Failed to remove ble.sh")
+            }
+            Teardown::Zoxide => runner
+                .run("rm", &["-f", &format!("{}/.local/bin/zoxide", home()?)])
+                .context("Failed to remove zoxide"),
+            Teardown::Tailscale => remove_apt(runner, &["tailscale".to_string()]),
+            Teardown::Doppler => remove_apt(runner, &["doppler".to_string()]),
+        }
+    }
+
+    pub fn format_dry_run(&self) -> String {
+        let (title, description, items): (&str, &str, &[String]) = match self {
+            Teardown::AptGet(pkgs) => ("AptGet", "(Remove system packages via apt-get)", pkgs),
+            Teardown::AptRepositories(names) => ("Apt", "(Remove apt repositories)", names),
+            Teardown::Pkgx(pkgs) => ("Pkgx", "(Uninstall tools via pkgm)", pkgs),
+            Teardown::Mise(tools) => ("Mise", "(Remove global tools from mise)", tools),
+            Teardown::Npm(pkgs) => ("Npm", "(Uninstall global npm packages)", pkgs),
+            Teardown::Nix(names) => ("Nix", "(Remove packages from the nix profile)", names),
+            Teardown::Stow => ("Stow", "(Unstow dotfiles)", &[]),
+            Teardown::BleSh(prefix) => {
+                ("BleSh", "(Uninstall ble.sh)", std::slice::from_ref(prefix))
+            }
+            Teardown::Zoxide => ("Zoxide", "(Uninstall zoxide)", &[]),
+            Teardown::Tailscale => ("Tailscale", "(Uninstall Tailscale)", &[]),
+            Teardown::Doppler => ("Doppler", "(Uninstall Doppler)", &[]),
+        };
+        let mut output = format!(
+            "{} {} {}",
+            "Remove".red().bold(),
+            title.magenta().bold(),
+            description.italic()
+        );
+        for item in items {
+            output.push_str(&format!("\n  - {}", item.magenta()));
+        }
+        output
+    }
+}
+
+fn home() -> Result<String, Error> {
+    let home = dirs::home_dir().ok_or_else(|| Error::msg("Failed to get home directory"))?;
+    Ok(home.to_string_lossy().to_string())
+}
+
+fn remove_apt(runner: &dyn CommandRunner, pkgs: &[String]) -> Result<(), Error> {
+    // held packages cannot be removed
+    let mut args: Vec<&str> = vec!["apt-mark", "unhold"];
+    args.extend(pkgs.iter().map(|s| s.as_str()));
+    runner
+        .run("sudo", &args)
+        .context("Failed to unhold apt packages")?;
+
+    let mut args: Vec<&str> = vec!["apt-get", "remove", "-y"];
+    args.extend(pkgs.iter().map(|s| s.as_str()));
+    runner
+        .run("sudo", &args)
+        .context("Failed to run apt-get remove")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::RecordingRunner;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_teardowns() {
+        let mut teardown = Teardown::for_section("apt-get", "tmux").unwrap();
+        teardown.merge(Teardown::for_section("apt-get", "httpie").unwrap());
+        teardown.merge(Teardown::for_section("apt-get", "tmux").unwrap());
+        assert_eq!(
+            teardown,
+            Teardown::AptGet(vec!["tmux".into(), "httpie".into()])
+        );
+        assert_eq!(teardown.section(), "apt-get");
+        assert_eq!(Teardown::for_section("curl", "bun"), None);
+    }
+
+    #[test]
+    fn test_teardown_commands() {
+        let runner = RecordingRunner::new();
+        Teardown::AptGet(vec!["tmux".into(), "httpie".into()])
+            .run(&runner)
+            .unwrap();
+        Teardown::Pkgx(vec!["glow".into()]).run(&runner).unwrap();
+        Teardown::Mise(vec!["node".into()]).run(&runner).unwrap();
+        Teardown::Npm(vec!["pnpm".into()]).run(&runner).unwrap();
+        Teardown::Nix(vec!["ripgrep".into()]).run(&runner).unwrap();
+        Teardown::Stow.run(&runner).unwrap();
+        Teardown::AptRepositories(vec!["vscode".into()])
+            .run(&runner)
+            .unwrap();
+        Teardown::BleSh("/opt/ble".into()).run(&runner).unwrap();
+        let home = home().unwrap();
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "sudo apt-mark unhold tmux httpie",
+                "sudo apt-get remove -y tmux httpie",
+                "pkgm uninstall glow",
+                "mise unuse -g node",
+                &format!("bash -c {NODE_ENV}; npm uninstall -g pnpm"),
+                "nix profile remove ripgrep",
+                "bash -c stow -D -d ~/.dotfiles -t ~ -- .",
+                "sudo rm -f /etc/apt/sources.list.d/vscode.sources /etc/apt/keyrings/vscode.gpg",
+                &format!(
+                    "rm -rf /opt/ble/share/blesh /opt/ble/share/doc/blesh /opt/ble/share/licenses/blesh {home}/.oh-my-droid/ble.sh {home}/ble.sh"
+                ),
+            ]
+        );
+    }
+}